@@ -0,0 +1,377 @@
+//! Audit trail of mutations.
+//!
+//! With an [AuditTrail](struct.AuditTrail.html) `insert_many`, `update_many` and `delete_many`
+//! write one audit row per inserted, updated or deleted row of every path into the audit table.
+//! Mutations with audit trail run in a transaction, or in a savepoint of an open transaction,
+//! so that the audit rows are committed or rolled back together with the mutation.
+//! Statements that affect no rows are not audited.
+//! Rows are read before they are deleted to record their keys and previous values.
+//!
+//! The audit table needs these columns:
+//!
+//! ```sql
+//! CREATE TABLE Audit (
+//!     id BIGINT UNSIGNED AUTO_INCREMENT PRIMARY KEY,
+//!     entity VARCHAR(255) NOT NULL,      -- Type name of the root entity
+//!     path VARCHAR(255) NOT NULL,        -- Field path, empty for the root
+//!     entity_key VARCHAR(255) NOT NULL,  -- Key of the inserted, updated or deleted row
+//!     operation VARCHAR(16) NOT NULL,    -- insert, update or delete
+//!     fields TEXT NOT NULL,              -- Written columns
+//!     old_values TEXT NULL,              -- Previous values of `update_changed` and of deleted rows
+//!     new_values TEXT NULL,
+//!     changed_by VARCHAR(255) NULL,      -- Value of the user aux param
+//!     roles TEXT NOT NULL,
+//!     changed_at DATETIME NOT NULL
+//! );
+//! ```
+//!
+//! ```ignore
+//! toql.set_audit_trail(Some(AuditTrail::new("Audit").with_user_param("user_id")));
+//! toql.update_many(fields!(User, "name"), &mut users)?;
+//! ```
+
+use std::collections::{HashMap, HashSet};
+
+use toql::{sql::Sql, sql_arg::SqlArg};
+
+use crate::{
+    diff::{InsertStatement, UpdateStatement},
+    error::{Result, ToqlMySqlError},
+    merge_diff::key_repr,
+};
+
+/// Kind of an audited mutation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditOperation {
+    Insert,
+    Update,
+    Delete,
+}
+
+impl AuditOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditOperation::Insert => "insert",
+            AuditOperation::Update => "update",
+            AuditOperation::Delete => "delete",
+        }
+    }
+}
+
+/// Audit row of one mutated row.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AuditEntry {
+    pub(crate) entity: String,
+    pub(crate) path: String,
+    pub(crate) key: String,
+    pub(crate) operation: AuditOperation,
+    pub(crate) fields: String,
+    pub(crate) old_values: Option<String>,
+    pub(crate) new_values: Option<String>,
+}
+
+impl AuditEntry {
+    /// Audit rows of an insert, one for each value tuple.
+    ///
+    /// The keys are the keys of the inserted entities in the order of the value tuples.
+    pub(crate) fn inserted(
+        entity: &str,
+        path: &str,
+        sql: &Sql,
+        keys: &[Vec<SqlArg>],
+    ) -> Result<Vec<Self>> {
+        let statement = InsertStatement::parse(sql)
+            .ok_or_else(|| ToqlMySqlError::UnexpectedStatement(sql.0.to_owned()))?;
+        let entries = statement
+            .tuples
+            .iter()
+            .enumerate()
+            .map(|(i, tuple)| {
+                let described = statement
+                    .columns
+                    .iter()
+                    .zip(tuple)
+                    .map(|(column, (value, args))| describe(column, value, args))
+                    .collect::<Vec<_>>();
+                AuditEntry {
+                    entity: entity.to_string(),
+                    path: path.to_string(),
+                    key: keys.get(i).map(|k| key_repr(k)).unwrap_or_default(),
+                    operation: AuditOperation::Insert,
+                    fields: statement.columns.join(", "),
+                    old_values: None,
+                    new_values: Some(described.join(", ")),
+                }
+            })
+            .collect();
+        Ok(entries)
+    }
+
+    /// Audit row of an update, previous values are taken from the update of the old entity.
+    pub(crate) fn updated(entity: &str, path: &str, sql: &Sql, old: Option<&Sql>) -> Self {
+        let statement = match UpdateStatement::parse(sql) {
+            Some(s) => s,
+            None => return Self::unparsed(entity, path, AuditOperation::Update, sql),
+        };
+        let old_statement = old.and_then(UpdateStatement::parse);
+
+        let mut fields = Vec::new();
+        let mut new_values = Vec::new();
+        let mut old_values = Vec::new();
+        for (assignment, args) in &statement.assignments {
+            let (column, value) = split_assignment(assignment);
+            fields.push(column);
+            new_values.push(describe(column, value, args));
+            let old_assignment = old_statement.as_ref().and_then(|o| {
+                o.assignments
+                    .iter()
+                    .find(|(a, _)| split_assignment(a).0 == column)
+            });
+            if let Some((a, aa)) = old_assignment {
+                old_values.push(describe(column, split_assignment(a).1, aa));
+            }
+        }
+        AuditEntry {
+            entity: entity.to_string(),
+            path: path.to_string(),
+            key: key_repr(statement.tail_args),
+            operation: AuditOperation::Update,
+            fields: fields.join(", "),
+            old_values: old_statement.map(|_| old_values.join(", ")),
+            new_values: Some(new_values.join(", ")),
+        }
+    }
+
+    /// Audit row of a deleted row with its key and its previous column values.
+    pub(crate) fn deleted(
+        entity: &str,
+        path: &str,
+        key: &[SqlArg],
+        old_values: &[(String, SqlArg)],
+    ) -> Self {
+        AuditEntry {
+            entity: entity.to_string(),
+            path: path.to_string(),
+            key: key_repr(key),
+            operation: AuditOperation::Delete,
+            fields: old_values
+                .iter()
+                .map(|(c, _)| c.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+            old_values: Some(
+                old_values
+                    .iter()
+                    .map(|(c, v)| describe(c, "?", std::slice::from_ref(v)))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            new_values: None,
+        }
+    }
+
+    fn unparsed(entity: &str, path: &str, operation: AuditOperation, sql: &Sql) -> Self {
+        AuditEntry {
+            entity: entity.to_string(),
+            path: path.to_string(),
+            key: key_repr(&sql.1),
+            operation,
+            fields: String::new(),
+            old_values: None,
+            new_values: None,
+        }
+    }
+}
+
+/// Audit table and the aux param that identifies the user.
+#[derive(Debug, Clone)]
+pub struct AuditTrail {
+    table: String,
+    user_param: Option<String>,
+}
+
+impl AuditTrail {
+    pub fn new<T: Into<String>>(table: T) -> Self {
+        AuditTrail {
+            table: table.into(),
+            user_param: None,
+        }
+    }
+
+    /// Record the value of this aux param as the changing user.
+    pub fn with_user_param<S: Into<String>>(mut self, user_param: S) -> Self {
+        self.user_param = Some(user_param.into());
+        self
+    }
+
+    pub fn table(&self) -> &str {
+        &self.table
+    }
+
+    pub fn user_param(&self) -> Option<&str> {
+        self.user_param.as_deref()
+    }
+
+    /// Build the insert of the audit rows, returns none without entries.
+    pub(crate) fn insert_sql(
+        &self,
+        entries: Vec<AuditEntry>,
+        aux_params: &HashMap<String, SqlArg>,
+        roles: &HashSet<String>,
+    ) -> Option<Sql> {
+        if entries.is_empty() {
+            return None;
+        }
+        let user = self
+            .user_param
+            .as_ref()
+            .and_then(|p| aux_params.get(p))
+            .cloned()
+            .unwrap_or(SqlArg::Null());
+        let mut roles = roles.iter().map(|r| r.as_str()).collect::<Vec<_>>();
+        roles.sort_unstable();
+        let roles = roles.join(",");
+
+        let tuples = vec!["(?, ?, ?, ?, ?, ?, ?, ?, ?, NOW())"; entries.len()].join(", ");
+        let mut args = Vec::with_capacity(entries.len() * 9);
+        for e in entries {
+            args.push(SqlArg::Str(e.entity));
+            args.push(SqlArg::Str(e.path));
+            args.push(SqlArg::Str(e.key));
+            args.push(SqlArg::Str(e.operation.as_str().to_string()));
+            args.push(SqlArg::Str(e.fields));
+            args.push(e.old_values.map_or(SqlArg::Null(), SqlArg::Str));
+            args.push(e.new_values.map_or(SqlArg::Null(), SqlArg::Str));
+            args.push(user.clone());
+            args.push(SqlArg::Str(roles.clone()));
+        }
+        Some(Sql(
+            format!(
+                "INSERT INTO {} (entity, path, entity_key, operation, fields, old_values, new_values, changed_by, roles, changed_at) VALUES {}",
+                self.table, tuples
+            ),
+            args,
+        ))
+    }
+}
+
+/// Split `column = value` into column and value expression.
+fn split_assignment(assignment: &str) -> (&str, &str) {
+    match assignment.find('=') {
+        Some(pos) => (assignment[..pos].trim(), assignment[pos + 1..].trim()),
+        None => (assignment.trim(), ""),
+    }
+}
+
+/// Describe a written value, placeholders are replaced with their arguments.
+fn describe(column: &str, value: &str, args: &[SqlArg]) -> String {
+    if args.is_empty() {
+        format!("{} = {}", column, value.trim())
+    } else {
+        format!("{} = {}", column, key_repr(args))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use toql::{sql::Sql, sql_arg::SqlArg};
+
+    use super::{AuditEntry, AuditOperation, AuditTrail};
+
+    #[test]
+    fn records_inserted_rows_with_entity_keys() {
+        let sql = Sql(
+            "INSERT INTO Phone (number, user_id) VALUES (?, ?), (?, ?)".to_string(),
+            vec![
+                SqlArg::from("123"),
+                SqlArg::U64(5),
+                SqlArg::from("456"),
+                SqlArg::U64(5),
+            ],
+        );
+        let keys = vec![
+            vec![SqlArg::from("123"), SqlArg::U64(5)],
+            vec![SqlArg::from("456"), SqlArg::U64(5)],
+        ];
+        let entries = AuditEntry::inserted("User", "phones", &sql, &keys).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].key, "'456',5");
+        assert_eq!(entries[1].operation, AuditOperation::Insert);
+        assert_eq!(entries[1].fields, "number, user_id");
+        assert_eq!(
+            entries[1].new_values.as_deref(),
+            Some("number = '456', user_id = 5")
+        );
+    }
+
+    #[test]
+    fn fails_for_unexpected_insert() {
+        let sql = Sql(
+            "INSERT INTO Phone SELECT * FROM OldPhone".to_string(),
+            vec![],
+        );
+        assert!(AuditEntry::inserted("User", "phones", &sql, &[]).is_err());
+    }
+
+    #[test]
+    fn records_old_and_new_values_of_update() {
+        let sql = Sql(
+            "UPDATE User user SET user.name = ?, user.age = ? WHERE user.id = ?".to_string(),
+            vec![SqlArg::from("Bob"), SqlArg::U64(31), SqlArg::U64(5)],
+        );
+        let old = Sql(
+            "UPDATE User user SET user.name = ? WHERE user.id = ?".to_string(),
+            vec![SqlArg::from("Alice"), SqlArg::U64(5)],
+        );
+        let entry = AuditEntry::updated("User", "", &sql, Some(&old));
+        assert_eq!(entry.key, "5");
+        assert_eq!(entry.fields, "user.name, user.age");
+        assert_eq!(entry.old_values.as_deref(), Some("user.name = 'Alice'"));
+        assert_eq!(
+            entry.new_values.as_deref(),
+            Some("user.name = 'Bob', user.age = 31")
+        );
+    }
+
+    #[test]
+    fn records_deleted_row_with_key_and_old_values() {
+        let values = vec![
+            ("id".to_string(), SqlArg::U64(5)),
+            ("name".to_string(), SqlArg::from("Alice")),
+        ];
+        let entry = AuditEntry::deleted("User", "", &[SqlArg::U64(5)], &values);
+        assert_eq!(entry.key, "5");
+        assert_eq!(entry.operation, AuditOperation::Delete);
+        assert_eq!(entry.fields, "id, name");
+        assert_eq!(entry.old_values.as_deref(), Some("id = 5, name = 'Alice'"));
+        assert_eq!(entry.new_values, None);
+    }
+
+    #[test]
+    fn builds_audit_insert() {
+        let trail = AuditTrail::new("Audit").with_user_param("user_id");
+        let mut aux_params = HashMap::new();
+        aux_params.insert("user_id".to_string(), SqlArg::U64(7));
+        let roles = vec!["b".to_string(), "a".to_string()]
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let entry = AuditEntry::deleted("User", "", &[SqlArg::U64(5)], &[]);
+
+        let sql = trail
+            .insert_sql(vec![entry.clone(), entry], &aux_params, &roles)
+            .unwrap();
+        assert!(sql
+            .0
+            .starts_with("INSERT INTO Audit (entity, path, entity_key"));
+        assert!(sql
+            .0
+            .ends_with("NOW()), (?, ?, ?, ?, ?, ?, ?, ?, ?, NOW())"));
+        assert_eq!(sql.1.len(), 18);
+        assert_eq!(sql.1[3], SqlArg::from("delete"));
+        assert_eq!(sql.1[7], SqlArg::U64(7));
+        assert_eq!(sql.1[8], SqlArg::from("a,b"));
+        assert!(trail.insert_sql(vec![], &aux_params, &roles).is_none());
+    }
+}
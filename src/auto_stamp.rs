@@ -0,0 +1,383 @@
+//! Automatic timestamps and user stamps.
+//!
+//! With [AutoStamps](struct.AutoStamps.html) inserts set the columns `created_at`, `updated_at`,
+//! `created_by` and `updated_by` and updates set `updated_at` and `updated_by`.
+//! Updates never change the created columns. Entities do not need to map these columns.
+//!
+//! Timestamps come from the server with `NOW()` or from a [Clock](trait.Clock.html).
+//! User stamps come from an aux param and are left alone, if the aux param is missing.
+//! Updates of kept merged entities in `MergeMode::Diff` are stamped like other updates.
+//! Merged entities that `MergeMode::Replace` deletes and inserts again keep their created columns.
+//!
+//! ```ignore
+//! let stamps = AutoStamps::new()
+//!     .with_user_param("user_id")
+//!     .without_table("UserRole");
+//! toql.set_auto_stamps(Some(stamps));
+//! toql.insert_one(paths!(top), &mut user)?;
+//! ```
+
+use std::{collections::HashSet, sync::Arc};
+
+use toql::{sql::Sql, sql_arg::SqlArg};
+
+use crate::{
+    diff::{InsertStatement, UpdateStatement},
+    error::{Result, ToqlMySqlError},
+    merge_diff::key_repr,
+};
+
+/// Source of the current time for timestamps.
+pub trait Clock: Send + Sync {
+    /// Returns the current time as SQL argument, for example `'2021-03-01 12:00:00'`.
+    fn now(&self) -> SqlArg;
+}
+
+/// Clock that always returns the same time.
+pub struct FixedClock(pub SqlArg);
+
+impl Clock for FixedClock {
+    fn now(&self) -> SqlArg {
+        self.0.clone()
+    }
+}
+
+/// Stamp columns, user aux param and clock.
+#[derive(Clone)]
+pub struct AutoStamps {
+    created_at: String,
+    updated_at: String,
+    created_by: String,
+    updated_by: String,
+    user_param: Option<String>,
+    clock: Option<Arc<dyn Clock>>,
+    skipped_tables: HashSet<String>,
+}
+
+impl Default for AutoStamps {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AutoStamps {
+    /// Stamp all tables with server time, without user stamps.
+    pub fn new() -> Self {
+        AutoStamps {
+            created_at: String::from("created_at"),
+            updated_at: String::from("updated_at"),
+            created_by: String::from("created_by"),
+            updated_by: String::from("updated_by"),
+            user_param: None,
+            clock: None,
+            skipped_tables: HashSet::new(),
+        }
+    }
+
+    /// Use other names for the timestamp columns.
+    pub fn with_timestamp_columns<S: Into<String>>(mut self, created_at: S, updated_at: S) -> Self {
+        self.created_at = created_at.into();
+        self.updated_at = updated_at.into();
+        self
+    }
+
+    /// Use other names for the user columns.
+    pub fn with_user_columns<S: Into<String>>(mut self, created_by: S, updated_by: S) -> Self {
+        self.created_by = created_by.into();
+        self.updated_by = updated_by.into();
+        self
+    }
+
+    /// Stamp users with the value of this aux param.
+    pub fn with_user_param<S: Into<String>>(mut self, user_param: S) -> Self {
+        self.user_param = Some(user_param.into());
+        self
+    }
+
+    /// Take timestamps from a clock instead of the server.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Do not stamp a table without stamp columns.
+    pub fn without_table<S: Into<String>>(mut self, table: S) -> Self {
+        self.skipped_tables.insert(table.into());
+        self
+    }
+
+    /// Set the stamp columns of an insert.
+    ///
+    /// Statements of skipped tables are returned unchanged, unexpected statements fail.
+    pub(crate) fn stamp_insert(&self, sql: Sql, user: Option<&SqlArg>) -> Result<Sql> {
+        let mut statement = match InsertStatement::parse(&sql) {
+            Some(s) if self.skips(s.table()) => return Ok(sql),
+            Some(s) => s,
+            None => return Err(ToqlMySqlError::UnexpectedStatement(sql.0)),
+        };
+        let (time, time_args) = self.time();
+        statement.set(&self.created_at, time, &time_args);
+        statement.set(&self.updated_at, time, &time_args);
+        if let Some(user) = user {
+            let user = std::slice::from_ref(user);
+            statement.set(&self.created_by, "?", user);
+            statement.set(&self.updated_by, "?", user);
+        }
+        Ok(statement.to_sql())
+    }
+
+    /// Set the created columns of an insert to the values of the rows that it replaces.
+    ///
+    /// The previous rows hold the name, the primary key flag and the value of their columns.
+    /// Tuples are matched to previous rows by their key, inserts without key columns are returned unchanged.
+    pub(crate) fn keep_created(
+        &self,
+        sql: Sql,
+        previous: &[Vec<(String, bool, SqlArg)>],
+    ) -> Result<Sql> {
+        if previous.is_empty() {
+            return Ok(sql);
+        }
+        let mut statement = match InsertStatement::parse(&sql) {
+            Some(s) if self.skips(s.table()) => return Ok(sql),
+            Some(s) => s,
+            None => return Err(ToqlMySqlError::UnexpectedStatement(sql.0)),
+        };
+        let position = |column: &str| {
+            statement
+                .columns
+                .iter()
+                .position(|c| c.trim_matches('`') == column)
+        };
+        let key_positions = previous[0]
+            .iter()
+            .filter(|(_, key, _)| *key)
+            .map(|(column, _, _)| position(column))
+            .collect::<Option<Vec<_>>>();
+        let key_positions = match key_positions {
+            Some(p) if !p.is_empty() => p,
+            _ => return Ok(sql),
+        };
+        let created = [&self.created_at, &self.created_by]
+            .iter()
+            .filter_map(|c| position(c).map(|p| (c.as_str(), p)))
+            .collect::<Vec<_>>();
+
+        for tuple in statement.tuples.iter_mut() {
+            let key = key_positions
+                .iter()
+                .flat_map(|p| tuple[*p].1.iter().cloned())
+                .collect::<Vec<_>>();
+            let key = key_repr(&key);
+            let row = previous.iter().find(|row| {
+                let row_key = row
+                    .iter()
+                    .filter(|(_, k, _)| *k)
+                    .map(|(_, _, v)| v.to_owned())
+                    .collect::<Vec<_>>();
+                key_repr(&row_key) == key
+            });
+            let row = match row {
+                Some(r) => r,
+                None => continue,
+            };
+            for (column, p) in &created {
+                let value = row.iter().find(|(c, _, _)| c == column);
+                if let Some((_, _, value)) = value {
+                    if *value != SqlArg::Null() {
+                        tuple[*p] = ("?".to_string(), vec![value.to_owned()]);
+                    }
+                }
+            }
+        }
+        Ok(statement.to_sql())
+    }
+
+    /// Set the update stamp columns of an update and drop assignments of the created columns.
+    ///
+    /// Statements of skipped tables are returned unchanged, unexpected statements fail.
+    pub(crate) fn stamp_update(&self, sql: Sql, user: Option<&SqlArg>) -> Result<Sql> {
+        let statement = match UpdateStatement::parse(&sql) {
+            Some(s) => s,
+            None => return Err(ToqlMySqlError::UnexpectedStatement(sql.0)),
+        };
+        let mut head = statement.head.split_whitespace().skip(1);
+        match head.next() {
+            Some(table) if !self.skips(table) => {}
+            Some(_) => return Ok(sql),
+            None => return Err(ToqlMySqlError::UnexpectedStatement(sql.0.to_owned())),
+        };
+        let qualifier = head.next().map(|a| format!("{}.", a)).unwrap_or_default();
+
+        let stamped = [
+            &self.created_at,
+            &self.updated_at,
+            &self.created_by,
+            &self.updated_by,
+        ];
+        let mut set = Vec::new();
+        let mut args = Vec::new();
+        for (assignment, assignment_args) in &statement.assignments {
+            let column = assignment.split('=').next().unwrap_or("").trim();
+            let column = column
+                .rsplit('.')
+                .next()
+                .unwrap_or(column)
+                .trim_matches('`');
+            if !stamped.iter().any(|s| s.as_str() == column) {
+                set.push(assignment.to_string());
+                args.extend_from_slice(assignment_args);
+            }
+        }
+        let (time, time_args) = self.time();
+        set.push(format!("{}{} = {}", qualifier, self.updated_at, time));
+        args.extend(time_args);
+        if let Some(user) = user {
+            set.push(format!("{}{} = ?", qualifier, self.updated_by));
+            args.push(user.to_owned());
+        }
+        args.extend_from_slice(statement.tail_args);
+
+        Ok(Sql(
+            format!(
+                "{} SET {}{}",
+                statement.head,
+                set.join(", "),
+                statement.tail
+            ),
+            args,
+        ))
+    }
+
+    /// Returns the user aux param.
+    pub fn user_param(&self) -> Option<&str> {
+        self.user_param.as_deref()
+    }
+
+    fn skips(&self, table: &str) -> bool {
+        self.skipped_tables.contains(table.trim_matches('`'))
+    }
+
+    fn time(&self) -> (&'static str, Vec<SqlArg>) {
+        match &self.clock {
+            Some(clock) => ("?", vec![clock.now()]),
+            None => ("NOW()", Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use toql::{sql::Sql, sql_arg::SqlArg};
+
+    use super::{AutoStamps, Clock, FixedClock};
+
+    fn insert() -> Sql {
+        Sql(
+            "INSERT INTO Phone (number, user_id) VALUES (?, ?), (?, ?)".to_string(),
+            vec![
+                SqlArg::from("123"),
+                SqlArg::U64(5),
+                SqlArg::from("456"),
+                SqlArg::U64(5),
+            ],
+        )
+    }
+
+    #[test]
+    fn fixed_clock_returns_its_time() {
+        let clock = FixedClock(SqlArg::from("2021-03-01 12:00:00"));
+        assert_eq!(clock.now(), SqlArg::from("2021-03-01 12:00:00"));
+        assert_eq!(clock.now(), clock.now());
+    }
+
+    #[test]
+    fn stamps_insert_with_server_time_and_user() {
+        let user = SqlArg::U64(7);
+        let sql = AutoStamps::new()
+            .stamp_insert(insert(), Some(&user))
+            .unwrap();
+        assert_eq!(
+            sql.0,
+            "INSERT INTO Phone (number, user_id, created_at, updated_at, created_by, updated_by) \
+             VALUES (?, ?, NOW(), NOW(), ?, ?), (?, ?, NOW(), NOW(), ?, ?)"
+        );
+        assert_eq!(sql.1.len(), 8);
+        assert_eq!(sql.1[3], SqlArg::U64(7));
+    }
+
+    #[test]
+    fn stamps_insert_with_clock() {
+        let time = SqlArg::from("2021-03-01 12:00:00");
+        let stamps = AutoStamps::new().with_clock(Arc::new(FixedClock(time.clone())));
+        let sql = stamps.stamp_insert(insert(), None).unwrap();
+        assert_eq!(
+            sql.0,
+            "INSERT INTO Phone (number, user_id, created_at, updated_at) \
+             VALUES (?, ?, ?, ?), (?, ?, ?, ?)"
+        );
+        assert_eq!(sql.1[2], time);
+        assert_eq!(sql.1[7], time);
+    }
+
+    #[test]
+    fn skips_tables() {
+        let stamps = AutoStamps::new().without_table("Phone");
+        assert_eq!(stamps.stamp_insert(insert(), None).unwrap(), insert());
+    }
+
+    #[test]
+    fn stamps_update_without_created_columns() {
+        let user = SqlArg::U64(7);
+        let sql = Sql(
+            "UPDATE User user SET user.name = ?, user.created_at = ? WHERE user.id = ?".to_string(),
+            vec![
+                SqlArg::from("Alice"),
+                SqlArg::from("2000-01-01"),
+                SqlArg::U64(5),
+            ],
+        );
+        let sql = AutoStamps::new().stamp_update(sql, Some(&user)).unwrap();
+        assert_eq!(
+            sql.0,
+            "UPDATE User user SET user.name = ?, user.updated_at = NOW(), user.updated_by = ? \
+             WHERE user.id = ?"
+        );
+        assert_eq!(
+            sql.1,
+            vec![SqlArg::from("Alice"), SqlArg::U64(7), SqlArg::U64(5)]
+        );
+    }
+
+    #[test]
+    fn fails_for_unexpected_statements() {
+        let stamps = AutoStamps::new();
+        let sql = Sql(
+            "INSERT INTO Phone SELECT * FROM OldPhone".to_string(),
+            vec![],
+        );
+        assert!(stamps.stamp_insert(sql, None).is_err());
+        let sql = Sql("UPDATE User user WHERE user.id = ?".to_string(), vec![]);
+        assert!(stamps.stamp_update(sql, None).is_err());
+    }
+
+    #[test]
+    fn keeps_created_stamps_of_replaced_rows() {
+        let stamps = AutoStamps::new();
+        let sql = stamps.stamp_insert(insert(), None).unwrap();
+        let previous = vec![vec![
+            ("number".to_string(), true, SqlArg::from("456")),
+            ("user_id".to_string(), true, SqlArg::U64(5)),
+            ("created_at".to_string(), false, SqlArg::from("2020-01-01")),
+        ]];
+        let sql = stamps.keep_created(sql, &previous).unwrap();
+        assert_eq!(
+            sql.0,
+            "INSERT INTO Phone (number, user_id, created_at, updated_at) \
+             VALUES (?, ?, NOW(), NOW()), (?, ?, ?, NOW())"
+        );
+        assert_eq!(sql.1[4], SqlArg::from("2020-01-01"));
+    }
+}
@@ -0,0 +1,227 @@
+//! Builder for the connection wrapper.
+//!
+//! [MySqlBuilder](struct.MySqlBuilder.html) configures the context and the options of a [MySql](../struct.MySql.html).
+//!
+//! ```ignore
+//! let mut toql = MySql::builder(&mut conn, &cache)
+//!     .with_roles(roles)
+//!     .with_aux_param("tenant_id", 5)
+//!     .with_alias_format(AliasFormat::TinyIndex)
+//!     .with_max_execution_time(Duration::from_secs(2))
+//!     .with_lock_mode(LockMode::Share)
+//!     .with_log_args(true)
+//!     .build();
+//! ```
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use toql::{alias::AliasFormat, backend::context::Context, cache::Cache, sql_arg::SqlArg};
+
+use crate::{
+    audit::AuditTrail,
+    auto_stamp::AutoStamps,
+    concurrency::VersionColumns,
+    connection::Connection,
+    load_options::LoadOptions,
+    lock_mode::LockMode,
+    merge_diff::MergeMode,
+    metrics::MetricsSink,
+    result_cache::ResultCache,
+    routing::ReadPreference,
+    slow_query::SlowQueryLog,
+    soft_delete::SoftDelete,
+    MySql,
+};
+
+/// Builder for [MySql](../struct.MySql.html).
+pub struct MySqlBuilder<'a, C: Connection> {
+    conn: &'a mut C,
+    cache: &'a Cache,
+    replica: Option<&'a mut C>,
+    read_preference: ReadPreference,
+    roles: HashSet<String>,
+    aux_params: HashMap<String, SqlArg>,
+    alias_format: AliasFormat,
+    max_execution_time: Option<Duration>,
+    load_options: Option<LoadOptions>,
+    lock_mode: LockMode,
+    require_updated_rows: bool,
+    merge_mode: MergeMode,
+    version_columns: Option<VersionColumns>,
+    soft_delete: Option<SoftDelete>,
+    audit_trail: Option<AuditTrail>,
+    auto_stamps: Option<AutoStamps>,
+    result_cache: Option<&'a ResultCache>,
+    log_args: bool,
+    metrics: Option<&'a dyn MetricsSink>,
+    slow_query_log: Option<SlowQueryLog>,
+}
+
+impl<'a, C: 'a + Connection> MySqlBuilder<'a, C> {
+    /// Create builder with canonical aliases, no roles and no aux params.
+    pub fn new(conn: &'a mut C, cache: &'a Cache) -> Self {
+        MySqlBuilder {
+            conn,
+            cache,
+            replica: None,
+            read_preference: ReadPreference::default(),
+            roles: HashSet::new(),
+            aux_params: HashMap::new(),
+            alias_format: AliasFormat::Canonical,
+            max_execution_time: None,
+            load_options: None,
+            lock_mode: LockMode::default(),
+            require_updated_rows: false,
+            merge_mode: MergeMode::default(),
+            version_columns: None,
+            soft_delete: None,
+            audit_trail: None,
+            auto_stamps: None,
+            result_cache: None,
+            log_args: false,
+            metrics: None,
+            slow_query_log: None,
+        }
+    }
+
+    pub fn with_roles(mut self, roles: HashSet<String>) -> Self {
+        self.roles = roles;
+        self
+    }
+
+    pub fn with_role<S: Into<String>>(mut self, role: S) -> Self {
+        self.roles.insert(role.into());
+        self
+    }
+
+    pub fn with_aux_params(mut self, aux_params: HashMap<String, SqlArg>) -> Self {
+        self.aux_params = aux_params;
+        self
+    }
+
+    pub fn with_aux_param<S: Into<String>, A: Into<SqlArg>>(mut self, name: S, value: A) -> Self {
+        self.aux_params.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn with_alias_format(mut self, alias_format: AliasFormat) -> Self {
+        self.alias_format = alias_format;
+        self
+    }
+
+    pub fn with_replica(mut self, replica: &'a mut C) -> Self {
+        self.replica = Some(replica);
+        self
+    }
+
+    pub fn with_read_preference(mut self, read_preference: ReadPreference) -> Self {
+        self.read_preference = read_preference;
+        self
+    }
+
+    /// Limit the execution time of root and merge selects.
+    pub fn with_max_execution_time(mut self, max_execution_time: Duration) -> Self {
+        self.max_execution_time = Some(max_execution_time);
+        self
+    }
+
+    pub fn with_load_options(mut self, load_options: LoadOptions) -> Self {
+        self.load_options = Some(load_options);
+        self
+    }
+
+    /// Default locking read for loads.
+    pub fn with_lock_mode(mut self, lock_mode: LockMode) -> Self {
+        self.lock_mode = lock_mode;
+        self
+    }
+
+    /// Fail updates of entities that affect no rows.
+    pub fn with_require_updated_rows(mut self, require_updated_rows: bool) -> Self {
+        self.require_updated_rows = require_updated_rows;
+        self
+    }
+
+    /// Write merged entities differentially.
+    pub fn with_merge_mode(mut self, merge_mode: MergeMode) -> Self {
+        self.merge_mode = merge_mode;
+        self
+    }
+
+    /// Check versions of entities updated with `update_one_versioned` and `update_many_versioned`.
+    pub fn with_version_columns(mut self, version_columns: VersionColumns) -> Self {
+        self.version_columns = Some(version_columns);
+        self
+    }
+
+    /// Mark rows as deleted instead of deleting them.
+    pub fn with_soft_delete(mut self, soft_delete: SoftDelete) -> Self {
+        self.soft_delete = Some(soft_delete);
+        self
+    }
+
+    /// Write audit rows of all mutations.
+    pub fn with_audit_trail(mut self, audit_trail: AuditTrail) -> Self {
+        self.audit_trail = Some(audit_trail);
+        self
+    }
+
+    /// Stamp inserted and updated rows with time and user.
+    pub fn with_auto_stamps(mut self, auto_stamps: AutoStamps) -> Self {
+        self.auto_stamps = Some(auto_stamps);
+        self
+    }
+
+    pub fn with_result_cache(mut self, result_cache: &'a ResultCache) -> Self {
+        self.result_cache = Some(result_cache);
+        self
+    }
+
+    /// Log argument values instead of redacting them.
+    pub fn with_log_args(mut self, log_args: bool) -> Self {
+        self.log_args = log_args;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: &'a dyn MetricsSink) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn with_slow_query_log(mut self, slow_query_log: SlowQueryLog) -> Self {
+        self.slow_query_log = Some(slow_query_log);
+        self
+    }
+
+    /// Build the connection wrapper.
+    pub fn build(self) -> MySql<'a, C> {
+        let context = Context {
+            roles: self.roles,
+            aux_params: self.aux_params,
+            alias_format: self.alias_format,
+        };
+        let mut mysql = MySql::with_context(self.conn, self.cache, context);
+        if let Some(replica) = self.replica {
+            mysql.set_replica(replica);
+        }
+        mysql
+            .set_read_preference(self.read_preference)
+            .set_max_execution_time(self.max_execution_time)
+            .set_load_options(self.load_options)
+            .set_lock_mode(self.lock_mode)
+            .set_require_updated_rows(self.require_updated_rows)
+            .set_merge_mode(self.merge_mode)
+            .set_version_columns(self.version_columns)
+            .set_soft_delete(self.soft_delete)
+            .set_audit_trail(self.audit_trail)
+            .set_auto_stamps(self.auto_stamps)
+            .set_result_cache(self.result_cache)
+            .set_log_args(self.log_args)
+            .set_metrics(self.metrics)
+            .set_slow_query_log(self.slow_query_log);
+        mysql
+    }
+}
@@ -0,0 +1,300 @@
+//! Optimistic concurrency control.
+//!
+//! [VersionColumns](struct.VersionColumns.html) designate a version field per mapper.
+//! Version checks are opt-in: `update_one_versioned` and `update_many_versioned` update root and joined entities
+//! of these mappers only, if the version of the database row still matches the version of the entity.
+//! The version is incremented or set to the current time in the same update.
+//! Otherwise the update fails with `ConcurrentModification` and the error contains the key of the entity.
+//! All other updates ignore the version columns.
+//!
+//! The version is checked, whether or not the version field is part of the updated fields.
+//! After a successful update the new version is handed to the entity with [Versioned](trait.Versioned.html),
+//! so the entity can be updated again without reloading it.
+//!
+//! ```ignore
+//! impl Versioned for User {
+//!     fn set_version(&mut self, path: &str, version: SqlArg) {
+//!         if let ("", SqlArg::U64(v)) = (path, version) {
+//!             self.version = v;
+//!         }
+//!     }
+//! }
+//!
+//! toql.set_version_columns(Some(
+//!     VersionColumns::new()
+//!         .counter("User", "version")
+//!         .timestamp("Address", "updated_at"),
+//! ));
+//! match toql.update_one_versioned(fields!(User, "name"), &mut user) {
+//!     Err(ToqlMySqlError::ConcurrentModification { key }) => println!("User {} changed meanwhile", key),
+//!     r => r?,
+//! };
+//! ```
+
+use std::collections::HashMap;
+
+use toql::{sql::Sql, sql_arg::SqlArg};
+
+use crate::{
+    diff::{assignment_column, UpdateStatement},
+    error::{Result, ToqlMySqlError},
+    merge_diff::key_repr,
+};
+
+/// How a version column is advanced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionKind {
+    /// Integer that is incremented
+    Counter,
+    /// Timestamp that is set to the current time with microseconds
+    Timestamp,
+}
+
+/// Entity that receives its new versions after updates.
+pub trait Versioned {
+    /// Set the new version of the root entity or of a joined entity, the root path is empty.
+    fn set_version(&mut self, path: &str, version: SqlArg);
+}
+
+/// Version fields by mapper name.
+#[derive(Debug, Clone, Default)]
+pub struct VersionColumns {
+    fields: HashMap<String, (String, VersionKind)>,
+}
+
+/// Expected version of an update.
+pub(crate) struct VersionCheck {
+    /// Key of the updated entity
+    pub(crate) key: String,
+    pub(crate) kind: VersionKind,
+    /// Version of the entity before the update
+    pub(crate) expected: SqlArg,
+    /// Select of the version column of the updated row
+    pub(crate) select: Sql,
+}
+
+impl VersionCheck {
+    /// Returns the new version of a counter, timestamps must be selected.
+    pub(crate) fn next_counter(&self) -> Option<SqlArg> {
+        match (self.kind, &self.expected) {
+            (VersionKind::Counter, SqlArg::U64(v)) => Some(SqlArg::U64(v + 1)),
+            (VersionKind::Counter, SqlArg::I64(v)) => Some(SqlArg::I64(v + 1)),
+            _ => None,
+        }
+    }
+}
+
+/// Version updates of the entities of a path by key.
+///
+/// A version update only assigns the version field of an entity.
+pub(crate) struct VersionUpdates {
+    pub(crate) field: String,
+    pub(crate) kind: VersionKind,
+    updates: HashMap<String, (usize, Sql)>,
+}
+
+impl VersionUpdates {
+    pub(crate) fn new(field: String, kind: VersionKind) -> Self {
+        VersionUpdates {
+            field,
+            kind,
+            updates: HashMap::new(),
+        }
+    }
+
+    /// Add the version update of the entity with the index.
+    pub(crate) fn insert(&mut self, index: usize, sql: Sql) -> Result<()> {
+        let key = match UpdateStatement::parse(&sql) {
+            Some(statement) => key_repr(statement.tail_args),
+            None => return Err(ToqlMySqlError::UnexpectedStatement(sql.0)),
+        };
+        self.updates.insert(key, (index, sql));
+        Ok(())
+    }
+
+    /// Returns the entity index and the version update for an update of the same row.
+    pub(crate) fn get(&self, sql: &Sql) -> Option<(usize, &Sql)> {
+        let statement = UpdateStatement::parse(sql)?;
+        self.updates
+            .get(&key_repr(statement.tail_args))
+            .map(|(i, s)| (*i, s))
+    }
+}
+
+impl VersionColumns {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use an integer version field for a mapper.
+    pub fn counter<M: Into<String>, F: Into<String>>(mut self, mapper: M, field: F) -> Self {
+        self.fields
+            .insert(mapper.into(), (field.into(), VersionKind::Counter));
+        self
+    }
+
+    /// Use a timestamp field, such as `updated_at`, as version for a mapper.
+    pub fn timestamp<M: Into<String>, F: Into<String>>(mut self, mapper: M, field: F) -> Self {
+        self.fields
+            .insert(mapper.into(), (field.into(), VersionKind::Timestamp));
+        self
+    }
+
+    /// Returns the version field of a mapper.
+    pub fn field(&self, mapper: &str) -> Option<(&str, VersionKind)> {
+        self.fields.get(mapper).map(|(f, k)| (f.as_str(), *k))
+    }
+}
+
+/// Add version predicate and version advance to an update.
+///
+/// The version update is an update of the same row, that only assigns the version field of the entity.
+/// An assignment of the version column in the update is replaced by the advance.
+pub(crate) fn apply_version(
+    sql: Sql,
+    version_sql: &Sql,
+    kind: VersionKind,
+) -> Result<(Sql, VersionCheck)> {
+    let (statement, version_statement) = match (
+        UpdateStatement::parse(&sql),
+        UpdateStatement::parse(version_sql),
+    ) {
+        (Some(s), Some(v)) if v.assignments.len() == 1 && v.assignments[0].1.len() == 1 => (s, v),
+        _ => return Err(ToqlMySqlError::UnexpectedStatement(sql.0.to_owned())),
+    };
+    let (version_assignment, expected) = version_statement.assignments[0];
+    let qualified = assignment_column(version_assignment);
+
+    let advance = match kind {
+        VersionKind::Counter => format!("{} = {} + 1", qualified, qualified),
+        VersionKind::Timestamp => format!("{} = CURRENT_TIMESTAMP(6)", qualified),
+    };
+    let mut set = Vec::new();
+    let mut args = Vec::new();
+    for (assignment, assignment_args) in &statement.assignments {
+        if assignment_column(assignment) != qualified {
+            set.push(assignment.to_string());
+            args.extend_from_slice(assignment_args);
+        }
+    }
+    set.push(advance);
+
+    let predicate = match statement.tail.trim_start().strip_prefix("WHERE ") {
+        Some(condition) => format!(" WHERE ({}) AND {} = ?", condition, qualified),
+        None => format!(" WHERE {} = ?", qualified),
+    };
+    args.extend_from_slice(statement.tail_args);
+    args.extend_from_slice(expected);
+
+    let table = statement.head.trim_start().trim_start_matches("UPDATE");
+    let check = VersionCheck {
+        key: key_repr(statement.tail_args),
+        kind,
+        expected: expected[0].to_owned(),
+        select: Sql(
+            format!("SELECT {} FROM{}{}", qualified, table, statement.tail),
+            statement.tail_args.to_vec(),
+        ),
+    };
+    Ok((
+        Sql(
+            format!("{} SET {}{}", statement.head, set.join(", "), predicate),
+            args,
+        ),
+        check,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use toql::{sql::Sql, sql_arg::SqlArg};
+
+    use super::{apply_version, VersionColumns, VersionKind, VersionUpdates};
+
+    fn version_update(version: u64) -> Sql {
+        Sql(
+            "UPDATE User user SET user.version = ? WHERE user.id = ?".to_string(),
+            vec![SqlArg::U64(version), SqlArg::U64(5)],
+        )
+    }
+
+    #[test]
+    fn checks_and_increments_counter() {
+        let sql = Sql(
+            "UPDATE User user SET user.name = ? WHERE user.id = ?".to_string(),
+            vec![SqlArg::from("Alice"), SqlArg::U64(5)],
+        );
+        let (sql, check) = apply_version(sql, &version_update(3), VersionKind::Counter).unwrap();
+        assert_eq!(
+            sql.0,
+            "UPDATE User user SET user.name = ?, user.version = user.version + 1 \
+             WHERE (user.id = ?) AND user.version = ?"
+        );
+        assert_eq!(
+            sql.1,
+            vec![SqlArg::from("Alice"), SqlArg::U64(5), SqlArg::U64(3)]
+        );
+        assert_eq!(check.key, "5");
+        assert_eq!(check.next_counter(), Some(SqlArg::U64(4)));
+        assert_eq!(
+            check.select.0,
+            "SELECT user.version FROM User user WHERE user.id = ?"
+        );
+    }
+
+    #[test]
+    fn replaces_version_assignment_with_timestamp() {
+        let sql = Sql(
+            "UPDATE User user SET user.version = ?, user.name = ? WHERE user.id = ?".to_string(),
+            vec![SqlArg::U64(9), SqlArg::from("Alice"), SqlArg::U64(5)],
+        );
+        let (sql, check) = apply_version(sql, &version_update(3), VersionKind::Timestamp).unwrap();
+        assert_eq!(
+            sql.0,
+            "UPDATE User user SET user.name = ?, user.version = CURRENT_TIMESTAMP(6) \
+             WHERE (user.id = ?) AND user.version = ?"
+        );
+        assert_eq!(check.next_counter(), None);
+    }
+
+    #[test]
+    fn rejects_unexpected_version_update() {
+        let sql = version_update(3);
+        let version_sql = Sql("UPDATE User user".to_string(), vec![]);
+        assert!(apply_version(sql, &version_sql, VersionKind::Counter).is_err());
+    }
+
+    #[test]
+    fn finds_version_updates_by_key() {
+        let mut updates = VersionUpdates::new("version".to_string(), VersionKind::Counter);
+        updates.insert(2, version_update(3)).unwrap();
+        let sql = Sql(
+            "UPDATE User user SET user.name = ? WHERE user.id = ?".to_string(),
+            vec![SqlArg::from("Alice"), SqlArg::U64(5)],
+        );
+        assert_eq!(updates.get(&sql).map(|(i, _)| i), Some(2));
+        let other = Sql(
+            "UPDATE User user SET user.name = ? WHERE user.id = ?".to_string(),
+            vec![SqlArg::from("Alice"), SqlArg::U64(6)],
+        );
+        assert!(updates.get(&other).is_none());
+        let unexpected = Sql("UPDATE User user".to_string(), vec![]);
+        assert!(updates.insert(3, unexpected).is_err());
+    }
+
+    #[test]
+    fn keys_version_fields_by_mapper() {
+        let columns = VersionColumns::new()
+            .counter("User", "version")
+            .timestamp("Address", "updated_at");
+        assert_eq!(
+            columns.field("User"),
+            Some(("version", VersionKind::Counter))
+        );
+        assert_eq!(
+            columns.field("Address"),
+            Some(("updated_at", VersionKind::Timestamp))
+        );
+        assert_eq!(columns.field("Phone"), None);
+    }
+}
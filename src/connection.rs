@@ -0,0 +1,83 @@
+//! Connection abstraction.
+//!
+//! [MySql](../struct.MySql.html) runs all statements through the [Connection](trait.Connection.html) trait.
+//! It is implemented for the [GenericConnection](../../mysql/prelude/trait.GenericConnection.html) types of the driver,
+//! that is for connections, pooled connections and transactions.
+//! With feature `mock` it is also implemented for the [MockConnection](../mock/struct.MockConnection.html).
+//!
+//! Statements are prepared through the driver, which caches prepared statements per physical connection.
+//! The cache size is set with `OptsBuilder::stmt_cache_size` and defaults to 10 statements,
+//! the least recently used statement is closed when the cache is full.
+//! Resetting a connection with `Conn::reset` closes all cached statements, e.g. after a schema change.
+//! A [StatementCache](../statement_cache/struct.StatementCache.html) wraps a connection and counts hits and misses of this cache.
+
+use mysql::{prelude::GenericConnection, Value};
+
+use crate::error::Result;
+
+/// Connection that Toql runs its statements on.
+pub trait Connection {
+    /// Run a select and return all rows.
+    fn select(&mut self, sql: &str, params: Vec<Value>) -> Result<Vec<mysql::Row>>;
+
+    /// Run an insert, update or delete.
+    ///
+    /// Returns the number of affected rows and the last insert id.
+    fn execute(&mut self, sql: &str, params: Vec<Value>) -> Result<(u64, u64)>;
+
+    /// Run a statement without params and result, such as `START TRANSACTION`.
+    fn query_drop(&mut self, sql: &str) -> Result<()>;
+
+    /// Returns true, if the connection is an open transaction.
+    ///
+    /// Toql then uses savepoints instead of starting a transaction,
+    /// which would commit the open transaction implicitly.
+    fn in_transaction(&self) -> bool {
+        false
+    }
+
+    /// Close all prepared statements that the connection caches.
+    fn reset_statements(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+macro_rules! impl_connection {
+    ($conn:ty, $in_transaction:expr, |$c:ident| $reset_statements:expr) => {
+        impl Connection for $conn {
+            fn select(&mut self, sql: &str, params: Vec<Value>) -> Result<Vec<mysql::Row>> {
+                let query_results = self.prep_exec(sql, params)?;
+                let mut rows = Vec::new();
+                for r in query_results {
+                    rows.push(r?);
+                }
+                Ok(rows)
+            }
+
+            fn execute(&mut self, sql: &str, params: Vec<Value>) -> Result<(u64, u64)> {
+                let mut stmt = self.prepare(sql)?;
+                let res = stmt.execute(params)?;
+                Ok((res.affected_rows(), res.last_insert_id()))
+            }
+
+            fn query_drop(&mut self, sql: &str) -> Result<()> {
+                self.query(sql)?;
+                Ok(())
+            }
+
+            fn in_transaction(&self) -> bool {
+                $in_transaction
+            }
+
+            fn reset_statements(&mut self) -> Result<()> {
+                let $c = self;
+                $reset_statements
+            }
+        }
+    };
+}
+
+impl_connection!(mysql::Conn, false, |conn| Ok(conn.reset()?));
+impl_connection!(mysql::PooledConn, false, |conn| Ok(conn.as_mut().reset()?));
+// Resetting the connection would roll back the transaction
+impl_connection!(mysql::Transaction<'_>, true, |_conn| Ok(()));
@@ -0,0 +1,472 @@
+//! Change detection for updates.
+//!
+//! `update_changed` and `update_changed_many` compare entities with their previous version.
+//! The comparison is limited to the fields and merges that the caller selects,
+//! changes outside of the selected fields are neither detected nor written.
+//! For every joined path the update statements of both versions are built and compared assignment by assignment.
+//! Assignments are matched by their column and only changed ones are sent,
+//! so concurrent edits of other columns are not overwritten.
+//! Merged entities are compared by their insert statements and updated in `MergeMode::Diff`, if they differ.
+//! Entities without previous version are skipped.
+//!
+//! ```ignore
+//! let old = toql.load_one(query!(User, "*, address_*, phones_* , id eq 5"))?;
+//! let mut new = old.clone();
+//! new.name = "Alice".to_string();
+//! let report = toql.update_changed(fields!(User, "*, address_*, phones"), &old, &mut new)?;
+//! ```
+
+use toql::{sql::Sql, sql_arg::SqlArg};
+
+use crate::merge_diff::key_repr;
+
+/// Update statement split into its parts.
+pub(crate) struct UpdateStatement<'a> {
+    /// `UPDATE table alias`
+    pub(crate) head: &'a str,
+    pub(crate) assignments: Vec<(&'a str, &'a [SqlArg])>,
+    /// ` WHERE ...` or empty
+    pub(crate) tail: &'a str,
+    pub(crate) tail_args: &'a [SqlArg],
+}
+
+impl<'a> UpdateStatement<'a> {
+    /// Split `UPDATE ... SET a = ?, b = ? WHERE ...`, returns none for an unexpected statement.
+    pub(crate) fn parse(sql: &'a Sql) -> Option<Self> {
+        let text = sql.0.as_str();
+        let set_pos = text.find(" SET ")?;
+        let head = &text[..set_pos];
+        let rest = &text[set_pos + " SET ".len()..];
+        let (set, tail) = match find_top_level(rest, " WHERE ") {
+            Some(pos) => (&rest[..pos], &rest[pos..]),
+            None => (rest, ""),
+        };
+
+        let mut args = sql.1.as_slice();
+        let mut assignments = Vec::new();
+        for assignment in split_top_level(set) {
+            let n = count_placeholders(assignment);
+            if n > args.len() {
+                return None;
+            }
+            let (assignment_args, rest_args) = args.split_at(n);
+            assignments.push((assignment.trim(), assignment_args));
+            args = rest_args;
+        }
+        if count_placeholders(tail) != args.len() {
+            return None;
+        }
+        Some(UpdateStatement {
+            head,
+            assignments,
+            tail,
+            tail_args: args,
+        })
+    }
+
+    /// Returns the assignment of a target column.
+    pub(crate) fn assignment(&self, column: &str) -> Option<(&'a str, &'a [SqlArg])> {
+        self.assignments
+            .iter()
+            .find(|(a, _)| assignment_column(a) == column)
+            .copied()
+    }
+
+    /// Returns true, if both updates have the same table and predicate.
+    fn same_target(&self, other: &UpdateStatement) -> bool {
+        self.head == other.head
+            && self.tail == other.tail
+            && key_repr(self.tail_args) == key_repr(other.tail_args)
+    }
+}
+
+/// Insert statement `INSERT INTO table (columns) VALUES (...), ... [tail]` split into its parts.
+pub(crate) struct InsertStatement<'a> {
+    /// `INSERT INTO table`
+    pub(crate) head: &'a str,
+    pub(crate) columns: Vec<String>,
+    /// Values of every tuple with their arguments
+    pub(crate) tuples: Vec<Vec<(String, Vec<SqlArg>)>>,
+    /// ` ON DUPLICATE KEY UPDATE ...` or empty
+    pub(crate) tail: &'a str,
+    pub(crate) tail_args: &'a [SqlArg],
+}
+
+impl<'a> InsertStatement<'a> {
+    /// Split insert, returns none for an unexpected statement.
+    pub(crate) fn parse(sql: &'a Sql) -> Option<Self> {
+        let text = sql.0.as_str();
+        let start = text.find('(')?;
+        let values_pos = find_top_level(text, " VALUES ")?;
+        if values_pos < start {
+            return None;
+        }
+        let end = start + text[start..values_pos].rfind(')')?;
+        let head = text[..start].trim_end();
+        let columns = text[start + 1..end]
+            .split(',')
+            .map(|c| c.trim().to_string())
+            .collect::<Vec<_>>();
+        let values = &text[values_pos + " VALUES ".len()..];
+        let (values, tail) = match find_top_level(values, " ON DUPLICATE KEY ") {
+            Some(pos) => (&values[..pos], &values[pos..]),
+            None => (values, ""),
+        };
+
+        let mut args = sql.1.as_slice();
+        let mut tuples = Vec::new();
+        for tuple in split_top_level(values) {
+            let tuple = tuple.trim().strip_prefix('(')?.strip_suffix(')')?;
+            let items = split_top_level(tuple);
+            if items.len() != columns.len() {
+                return None;
+            }
+            let mut values = Vec::with_capacity(items.len());
+            for item in items {
+                let n = count_placeholders(item);
+                if n > args.len() {
+                    return None;
+                }
+                let (item_args, rest) = args.split_at(n);
+                values.push((item.trim().to_string(), item_args.to_vec()));
+                args = rest;
+            }
+            tuples.push(values);
+        }
+        if count_placeholders(tail) != args.len() {
+            return None;
+        }
+        Some(InsertStatement {
+            head,
+            columns,
+            tuples,
+            tail,
+            tail_args: args,
+        })
+    }
+
+    pub(crate) fn table(&self) -> &str {
+        self.head.split_whitespace().last().unwrap_or("")
+    }
+
+    /// Set the value of a column in all tuples, adds the column if missing.
+    pub(crate) fn set(&mut self, column: &str, value: &str, args: &[SqlArg]) {
+        let value = (value.to_string(), args.to_vec());
+        match self
+            .columns
+            .iter()
+            .position(|c| c.trim_matches('`') == column)
+        {
+            Some(i) => self.tuples.iter_mut().for_each(|t| t[i] = value.clone()),
+            None => {
+                self.columns.push(column.to_string());
+                self.tuples.iter_mut().for_each(|t| t.push(value.clone()));
+            }
+        }
+    }
+
+    /// Keep the value tuples, for which `keep` is true.
+    pub(crate) fn retain_tuples(&mut self, keep: &[bool]) {
+        let mut keep = keep.iter();
+        self.tuples
+            .retain(|_| keep.next().copied().unwrap_or(false));
+    }
+
+    pub(crate) fn to_sql(&self) -> Sql {
+        let mut args = Vec::new();
+        let tuples = self
+            .tuples
+            .iter()
+            .map(|t| {
+                let values = t
+                    .iter()
+                    .map(|(v, a)| {
+                        args.extend_from_slice(a);
+                        v.as_str()
+                    })
+                    .collect::<Vec<_>>();
+                format!("({})", values.join(", "))
+            })
+            .collect::<Vec<_>>();
+        args.extend_from_slice(self.tail_args);
+        Sql(
+            format!(
+                "{} ({}) VALUES {}{}",
+                self.head,
+                self.columns.join(", "),
+                tuples.join(", "),
+                self.tail
+            ),
+            args,
+        )
+    }
+}
+
+/// Delete statement `DELETE alias FROM table alias ...` split into its parts.
+pub(crate) struct DeleteStatement<'a> {
+    pub(crate) alias: &'a str,
+    pub(crate) table: &'a str,
+    /// Table reference, joins and predicate
+    pub(crate) from: &'a str,
+}
+
+impl<'a> DeleteStatement<'a> {
+    /// Split delete, returns none for an unexpected statement.
+    pub(crate) fn parse(sql: &'a str) -> Option<Self> {
+        let rest = sql.trim_start().strip_prefix("DELETE ")?;
+        let from_pos = rest.find(" FROM ")?;
+        let alias = rest[..from_pos].trim();
+        let from = rest[from_pos + " FROM ".len()..].trim();
+        let mut tokens = from.split_whitespace();
+        let table = tokens.next()?;
+        if tokens.next()? != alias {
+            return None;
+        }
+        Some(DeleteStatement { alias, table, from })
+    }
+}
+
+/// Returns the update with the changed assignments of the new statement.
+///
+/// Assignments are matched by their target column.
+/// Returns none, if nothing changed. Statements that cannot be compared are returned unchanged.
+pub(crate) fn changed_update(old: &Sql, new: &Sql) -> Option<Sql> {
+    let (old_statement, new_statement) =
+        match (UpdateStatement::parse(old), UpdateStatement::parse(new)) {
+            (Some(o), Some(n)) if o.same_target(&n) => (o, n),
+            _ => return Some(new.to_owned()),
+        };
+
+    let mut set = Vec::new();
+    let mut args = Vec::new();
+    for (assignment, assignment_args) in &new_statement.assignments {
+        let unchanged = old_statement
+            .assignment(assignment_column(assignment))
+            .map_or(false, |(a, aa)| {
+                a == *assignment && key_repr(aa) == key_repr(assignment_args)
+            });
+        if !unchanged {
+            set.push(*assignment);
+            args.extend_from_slice(assignment_args);
+        }
+    }
+    if set.is_empty() {
+        return None;
+    }
+    args.extend_from_slice(new_statement.tail_args);
+    Some(Sql(
+        format!(
+            "{} SET {}{}",
+            new_statement.head,
+            set.join(", "),
+            new_statement.tail
+        ),
+        args,
+    ))
+}
+
+/// Returns true, if both updates change the same table rows.
+pub(crate) fn same_target(a: &Sql, b: &Sql) -> bool {
+    match (UpdateStatement::parse(a), UpdateStatement::parse(b)) {
+        (Some(a), Some(b)) => a.same_target(&b),
+        _ => false,
+    }
+}
+
+/// Returns the target column of an assignment `column = value`.
+pub(crate) fn assignment_column(assignment: &str) -> &str {
+    assignment.split('=').next().unwrap_or("").trim()
+}
+
+/// Returns true, if both statements have the same SQL and arguments.
+pub(crate) fn same_sql(a: &Option<Sql>, b: &Option<Sql>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.0 == b.0 && key_repr(&a.1) == key_repr(&b.1),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+pub(crate) fn count_placeholders(sql: &str) -> usize {
+    let mut count = 0;
+    scan(sql, |_, c, _| {
+        if c == '?' {
+            count += 1;
+        }
+    });
+    count
+}
+
+/// Split on commas outside of parentheses and string literals.
+pub(crate) fn split_top_level(sql: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    scan(sql, |i, c, depth| {
+        if c == ',' && depth == 0 {
+            parts.push(&sql[start..i]);
+            start = i + 1;
+        }
+    });
+    parts.push(&sql[start..]);
+    parts
+}
+
+/// Find pattern outside of parentheses and string literals.
+pub(crate) fn find_top_level(sql: &str, pattern: &str) -> Option<usize> {
+    let mut found = None;
+    scan(sql, |i, _, depth| {
+        if found.is_none() && depth == 0 && sql[i..].starts_with(pattern) {
+            found = Some(i);
+        }
+    });
+    found
+}
+
+/// Call function for every character outside of string literals with the parenthesis depth.
+pub(crate) fn scan<F: FnMut(usize, char, i32)>(sql: &str, mut f: F) {
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (i, c) in sql.char_indices() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '\'' | '"' | '`' => quote = Some(c),
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => {}
+        }
+        f(i, c, depth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use toql::{sql::Sql, sql_arg::SqlArg};
+
+    use super::{changed_update, same_target, split_top_level, InsertStatement, UpdateStatement};
+    use crate::merge_diff::key_repr;
+
+    fn update(sql: &str, args: Vec<SqlArg>) -> Sql {
+        Sql(sql.to_string(), args)
+    }
+
+    #[test]
+    fn parses_update() {
+        let sql = update(
+            "UPDATE User user SET user.name = ?, user.age = ? WHERE user.id = ?",
+            vec![SqlArg::from("Alice"), SqlArg::U64(30), SqlArg::U64(5)],
+        );
+        let statement = UpdateStatement::parse(&sql).unwrap();
+        assert_eq!(statement.head, "UPDATE User user");
+        assert_eq!(statement.assignments.len(), 2);
+        assert_eq!(key_repr(statement.assignment("user.age").unwrap().1), "30");
+        assert_eq!(statement.tail, " WHERE user.id = ?");
+        assert_eq!(key_repr(statement.tail_args), "5");
+    }
+
+    #[test]
+    fn rejects_update_with_wrong_arguments() {
+        let sql = update(
+            "UPDATE User user SET user.name = ? WHERE user.id = ?",
+            vec![],
+        );
+        assert!(UpdateStatement::parse(&sql).is_none());
+    }
+
+    #[test]
+    fn keeps_changed_assignments() {
+        let old = update(
+            "UPDATE User user SET user.name = ?, user.age = ? WHERE user.id = ?",
+            vec![SqlArg::from("Alice"), SqlArg::U64(30), SqlArg::U64(5)],
+        );
+        let new = update(
+            "UPDATE User user SET user.name = ?, user.age = ? WHERE user.id = ?",
+            vec![SqlArg::from("Alice"), SqlArg::U64(31), SqlArg::U64(5)],
+        );
+        let changed = changed_update(&old, &new).unwrap();
+        assert_eq!(
+            changed.0,
+            "UPDATE User user SET user.age = ? WHERE user.id = ?"
+        );
+        assert_eq!(key_repr(&changed.1), "31,5");
+    }
+
+    #[test]
+    fn matches_assignments_by_column() {
+        let old = update(
+            "UPDATE User user SET user.age = ?, user.name = ? WHERE user.id = ?",
+            vec![SqlArg::U64(30), SqlArg::from("Alice"), SqlArg::U64(5)],
+        );
+        let new = update(
+            "UPDATE User user SET user.nickname = ?, user.name = ?, user.age = ? WHERE user.id = ?",
+            vec![
+                SqlArg::Null(),
+                SqlArg::from("Alice"),
+                SqlArg::U64(30),
+                SqlArg::U64(5),
+            ],
+        );
+        let changed = changed_update(&old, &new).unwrap();
+        assert_eq!(
+            changed.0,
+            "UPDATE User user SET user.nickname = ? WHERE user.id = ?"
+        );
+    }
+
+    #[test]
+    fn returns_nothing_without_changes() {
+        let old = update(
+            "UPDATE User user SET user.name = ? WHERE user.id = ?",
+            vec![SqlArg::from("Alice"), SqlArg::U64(5)],
+        );
+        assert!(changed_update(&old, &old.to_owned()).is_none());
+    }
+
+    #[test]
+    fn returns_update_of_other_rows_unchanged() {
+        let old = update(
+            "UPDATE User user SET user.name = ? WHERE user.id = ?",
+            vec![SqlArg::from("Alice"), SqlArg::U64(5)],
+        );
+        let new = update(
+            "UPDATE User user SET user.name = ? WHERE user.id = ?",
+            vec![SqlArg::from("Alice"), SqlArg::U64(6)],
+        );
+        assert!(!same_target(&old, &new));
+        assert_eq!(changed_update(&old, &new).unwrap().0, new.0);
+    }
+
+    #[test]
+    fn splits_outside_of_parentheses_and_literals() {
+        assert_eq!(
+            split_top_level("a = CONCAT(?, ','), b = ','"),
+            vec!["a = CONCAT(?, ',')", " b = ','"]
+        );
+    }
+
+    #[test]
+    fn retains_insert_tuples() {
+        let sql = Sql(
+            "INSERT INTO Phone (user_id, number) VALUES (?, ?), (?, ?), (?, ?)".to_string(),
+            (1..=6).map(SqlArg::U64).collect(),
+        );
+        let mut statement = InsertStatement::parse(&sql).unwrap();
+        assert_eq!(statement.table(), "Phone");
+        statement.retain_tuples(&[true, false, true]);
+        let sql = statement.to_sql();
+        assert_eq!(
+            sql.0,
+            "INSERT INTO Phone (user_id, number) VALUES (?, ?), (?, ?)"
+        );
+        assert_eq!(key_repr(&sql.1), "1,2,5,6");
+    }
+}
@@ -0,0 +1,135 @@
+//! Dry run for mutations.
+//!
+//! In dry run mode `insert_many`, `update_many` and `delete_many` build all statements as usual,
+//! but record them in execution order instead of executing them.
+//! Inserts synthesize ids, so that generated keys are propagated to joined and merged entities.
+//!
+//! ```ignore
+//! toql.set_dry_run(true);
+//! toql.insert_many(paths!(User, "phones"), &mut users)?;
+//! for s in toql.take_recorded_statements() {
+//!     println!("{} `{}`: {}", s.kind.as_str(), s.path, s.sql.to_unsafe_string());
+//! }
+//! ```
+
+use toql::sql::Sql;
+
+use crate::metrics::StatementKind;
+
+/// Statement that was recorded instead of executed.
+pub struct RecordedStatement {
+    pub kind: StatementKind,
+    /// Field path of the statement, empty for the root entity.
+    pub path: String,
+    pub sql: Sql,
+}
+
+/// Recorded statements and id generator of a dry run.
+pub(crate) struct DryRun {
+    pub(crate) statements: Vec<RecordedStatement>,
+    next_id: u64,
+}
+
+impl DryRun {
+    pub(crate) fn new() -> Self {
+        DryRun {
+            statements: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    /// Record statement and return synthesized affected rows and last insert id.
+    ///
+    /// Updates and deletes report no affected rows, because they depend on the database content.
+    pub(crate) fn record(&mut self, kind: StatementKind, path: &str, sql: Sql) -> (u64, u64) {
+        let (affected_rows, last_insert_id) = if kind == StatementKind::Insert {
+            let rows = count_insert_rows(&sql.0);
+            let id = self.next_id;
+            self.next_id += rows;
+            (rows, id)
+        } else {
+            (0, 0)
+        };
+        self.statements.push(RecordedStatement {
+            kind,
+            path: path.to_string(),
+            sql,
+        });
+        (affected_rows, last_insert_id)
+    }
+}
+
+/// Count the value tuples of an insert statement.
+pub(crate) fn count_insert_rows(sql: &str) -> u64 {
+    let values = match sql.find("VALUES") {
+        Some(pos) => &sql[pos + "VALUES".len()..],
+        None => return 0,
+    };
+
+    let mut rows = 0;
+    let mut depth = 0;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for c in values.chars() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '\'' | '"' => quote = Some(c),
+            '(' => {
+                if depth == 0 {
+                    rows += 1;
+                }
+                depth += 1;
+            }
+            ')' => depth -= 1,
+            _ if depth == 0 && c.is_alphabetic() => break, // ON DUPLICATE KEY ...
+            _ => {}
+        }
+    }
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::count_insert_rows;
+
+    #[test]
+    fn counts_value_tuples() {
+        assert_eq!(count_insert_rows("INSERT INTO User (id, name) VALUES (?, ?)"), 1);
+        assert_eq!(
+            count_insert_rows("INSERT INTO User (id, name) VALUES (?, ?), (?, ?), (?, ?)"),
+            3
+        );
+    }
+
+    #[test]
+    fn ignores_nested_parentheses_and_literals() {
+        assert_eq!(
+            count_insert_rows("INSERT INTO User (id, name) VALUES (?, CONCAT('(', ?)), (?, ')')"),
+            2
+        );
+    }
+
+    #[test]
+    fn stops_at_upsert_clause() {
+        assert_eq!(
+            count_insert_rows(
+                "INSERT INTO User (id, name) VALUES (?, ?) ON DUPLICATE KEY UPDATE name = VALUES(name)"
+            ),
+            1
+        );
+    }
+
+    #[test]
+    fn counts_nothing_without_values() {
+        assert_eq!(count_insert_rows("DELETE FROM User WHERE id = ?"), 0);
+    }
+}
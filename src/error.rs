@@ -1,4 +1,4 @@
-use mysql::Error;
+use mysql::{error::MySqlError, Error};
 /// MySQL failed to run the SQL query. For feature `mysql`
 use toql::{error::ToqlError, sql_builder::sql_builder_error::SqlBuilderError};
 
@@ -6,11 +6,39 @@ use toql::{error::ToqlError, sql_builder::sql_builder_error::SqlBuilderError};
 pub enum ToqlMySqlError {
     ToqlError(ToqlError),
     MySqlError(Error),
+    /// Query exceeded maximum execution time.
+    QueryTimeout(MySqlError),
+    /// Optimizer or index hint is invalid.
+    InvalidHint(String),
+    /// Session variable does not match session settings (variable, expected, actual).
+    SessionMismatch(String, String, String),
+    /// Update of an entity affected no rows, the entity vanished (path, key, SQL).
+    NoRowsUpdated(String, String, String),
+    /// Version of an entity changed since it was loaded.
+    ConcurrentModification { key: String },
+    /// Versioned update has no version check, e.g. the version field is not set (path, field).
+    MissingVersion(String, String),
+    /// Generated statement could not be rewritten, because it has an unexpected form (SQL).
+    UnexpectedStatement(String),
+    #[cfg(feature = "r2d2")]
+    R2d2Error(r2d2::Error),
 }
 
+/// Server error code for statements interrupted by `MAX_EXECUTION_TIME`.
+const ER_QUERY_TIMEOUT: u16 = 3024;
+
 impl From<Error> for ToqlMySqlError {
     fn from(err: Error) -> ToqlMySqlError {
-        ToqlMySqlError::MySqlError(err)
+        match err {
+            Error::MySqlError(e) if e.code == ER_QUERY_TIMEOUT => ToqlMySqlError::QueryTimeout(e),
+            _ => ToqlMySqlError::MySqlError(err),
+        }
+    }
+}
+#[cfg(feature = "r2d2")]
+impl From<r2d2::Error> for ToqlMySqlError {
+    fn from(err: r2d2::Error) -> ToqlMySqlError {
+        ToqlMySqlError::R2d2Error(err)
     }
 }
 impl From<ToqlError> for ToqlMySqlError {
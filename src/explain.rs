@@ -0,0 +1,28 @@
+//! Explain loads without running them.
+//!
+//! [MySql::explain](../struct.MySql.html#method.explain) returns every statement that a load would execute,
+//! in execution order: the root select, the count selects for counted pages and the merge selects for each path.
+//!
+//! Merge selects are restricted at runtime to the keys of the loaded entities.
+//! Because no entities are loaded, explained merge selects contain no key predicate.
+//!
+//! ```ignore
+//! let statements = toql.explain_with_plans(query!(User, "*, phones_*"), Some(Page::Counted(0, 10)))?;
+//! for s in statements {
+//!     println!("{} `{}`: {}", s.kind.as_str(), s.path, s.sql.0);
+//! }
+//! ```
+
+use toql::sql::Sql;
+
+use crate::metrics::StatementKind;
+
+/// Statement of a load.
+pub struct ExplainedStatement {
+    pub kind: StatementKind,
+    /// Field path of the statement, empty for the root entity.
+    pub path: String,
+    pub sql: Sql,
+    /// Query plan from `EXPLAIN FORMAT=JSON`, if requested.
+    pub plan: Option<String>,
+}
@@ -0,0 +1,90 @@
+//! Instrumentation of Toql operations.
+//!
+//! With feature `tracing` every operation of [MySql](../struct.MySql.html) runs in a span
+//! with the type name, the number of rows and the duration.
+//! Every statement emits an event with its kind, path and SQL.
+//! Without feature `tracing` statements are logged with `log` on debug level.
+//!
+//! Argument values may contain personal data and are redacted,
+//! unless they are explicitly enabled with `MySql::set_log_args`.
+
+use std::time::Instant;
+
+use toql::sql_arg::SqlArg;
+
+use crate::metrics::StatementKind;
+
+/// Running operation, records its duration when dropped.
+pub(crate) struct Operation {
+    #[cfg(feature = "tracing")]
+    span: tracing::span::EnteredSpan,
+    #[cfg(not(feature = "tracing"))]
+    name: &'static str,
+    start: Instant,
+}
+
+impl Operation {
+    pub(crate) fn new(name: &'static str, type_name: &str) -> Self {
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::info_span!(
+                "toql_mysql",
+                operation = name,
+                type_name = type_name,
+                rows = tracing::field::Empty,
+                duration_ms = tracing::field::Empty,
+            );
+            Operation {
+                span: span.entered(),
+                start: Instant::now(),
+            }
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            log::trace!("Start {} of `{}`", name, type_name);
+            Operation {
+                name,
+                start: Instant::now(),
+            }
+        }
+    }
+
+    /// Record the number of rows read or affected.
+    pub(crate) fn rows(&self, rows: u64) {
+        #[cfg(feature = "tracing")]
+        self.span.record("rows", &rows);
+        #[cfg(not(feature = "tracing"))]
+        log::trace!("{} read or affected {} rows", self.name, rows);
+    }
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        let duration_ms = self.start.elapsed().as_millis() as u64;
+        #[cfg(feature = "tracing")]
+        self.span.record("duration_ms", &duration_ms);
+        #[cfg(not(feature = "tracing"))]
+        log::trace!("{} took {} ms", self.name, duration_ms);
+    }
+}
+
+/// Emit event for a statement.
+///
+/// The path is empty for the root.
+pub(crate) fn statement(kind: StatementKind, path: &str, sql: &str, args: &[SqlArg], log_args: bool) {
+    let kind = kind.as_str();
+    let args = format_args(args, log_args);
+    #[cfg(feature = "tracing")]
+    tracing::debug!(kind, path, sql, args = %args, "statement");
+    #[cfg(not(feature = "tracing"))]
+    log::debug!("{} `{}` for path `{}` with {}", kind, sql, path, args);
+}
+
+/// Format arguments, values are redacted unless enabled.
+pub(crate) fn format_args(args: &[SqlArg], log_args: bool) -> String {
+    if log_args {
+        format!("{:?}", args)
+    } else {
+        format!("{} redacted args", args.len())
+    }
+}
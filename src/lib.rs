@@ -5,7 +5,10 @@
 //!
 
 
-use mysql::{prelude::GenericConnection};
+use mysql::consts::ColumnFlags;
+use mysql::prelude::GenericConnection;
+
+use crate::connection::Connection;
 
 use crate::row::Row;
 
@@ -21,21 +24,19 @@ use toql::query::{field_path::FieldPath, Query};
 use toql::sql_mapper_registry::SqlMapperRegistry;
 
 use toql::error::ToqlError;
-use toql::sql_builder::SqlBuilder;
+use toql::sql_builder::{build_result::BuildResult, SqlBuilder};
 
 use core::borrow::Borrow;
 use toql::alias::AliasFormat;
-use toql::log_sql;
 
 //use crate::row::FromResultRow;
 use std::{
     borrow::BorrowMut,
-    collections::{HashMap, HashSet}, sync::RwLockReadGuard,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque}, sync::RwLockReadGuard, time::{Duration, Instant},
 };
 use toql::fields::Fields;
 use toql::paths::Paths;
 
-//pub mod diff;
 //pub mod insert;
 //pub mod row;
 //pub mod insert;
@@ -43,19 +44,61 @@ use toql::paths::Paths;
 
 #[macro_use]
 pub mod access;
+pub mod audit;
+pub mod auto_stamp;
 
 //pub mod select;
 pub use mysql; // Reexport for derive produced code
 
 pub mod sql_arg;
 
+pub mod builder;
+pub mod concurrency;
+pub mod connection;
+pub mod diff;
+pub mod dry_run;
 pub mod error;
+pub mod explain;
+mod instrument;
+pub mod load_options;
+pub mod lock_mode;
+pub mod merge_diff;
+pub mod metrics;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod pool;
+pub mod report;
+pub mod result_cache;
+pub mod routing;
 pub mod row;
+pub mod session;
+pub mod slow_query;
+pub mod soft_delete;
+#[cfg(feature = "mock")]
+pub mod snapshot;
+pub mod statement_cache;
 
 
 
+use crate::audit::{AuditEntry, AuditTrail};
+use crate::auto_stamp::AutoStamps;
+use crate::builder::MySqlBuilder;
+use crate::concurrency::{self, VersionColumns, VersionKind, VersionUpdates, Versioned};
+use crate::dry_run::{DryRun, RecordedStatement};
 use crate::error::Result;
 use crate::error::ToqlMySqlError;
+use crate::explain::ExplainedStatement;
+use crate::instrument::Operation;
+use crate::load_options::{self, LoadOptions};
+use crate::lock_mode::LockMode;
+use crate::merge_diff::{self, MergedRows, MergeMode};
+use crate::metrics::{MetricsSink, StatementKind, StatementMetrics};
+use crate::report::{InsertReport, SyncReport, UpdateReport};
+use crate::result_cache::ResultCache;
+use crate::routing::ReadPreference;
+use crate::session::SessionSettings;
+use crate::slow_query::SlowQueryLog;
+use crate::soft_delete::{DeletedColumns, SoftDelete};
 use toql::sql::Sql;
 use toql::sql_arg::SqlArg;
 use toql::tree::tree_predicate::TreePredicate;
@@ -71,7 +114,45 @@ use toql::{
     sql_mapper::{mapped::Mapped}, backend::context::Context, cache::Cache,
 };
 
-use crate::sql_arg::{values_from, values_from_ref};
+use crate::sql_arg::{arg_from, values_from, values_from_ref};
+
+/// Statement to retrieve the number of rows for `SQL_CALC_FOUND_ROWS`.
+const FOUND_ROWS_SQL: &str = "SELECT FOUND_ROWS();";
+
+/// Server error code for an insert of an existing key.
+const ER_DUP_ENTRY: u16 = 1062;
+
+/// Returns the error of the server for an insert of an existing key.
+fn duplicate_key_error(message: String) -> ToqlMySqlError {
+    ToqlMySqlError::MySqlError(mysql::Error::MySqlError(mysql::error::MySqlError {
+        state: "23000".to_string(),
+        message,
+        code: ER_DUP_ENTRY,
+    }))
+}
+
+fn build_count_sql<T, B, C>(mysql: &MySql<C>, query: &B) -> Result<Sql>
+where
+    T: Mapped,
+    B: Borrow<Query<T>>,
+    C: Connection,
+{
+    let ty = <T as Mapped>::type_name();
+    let mut alias_translator = AliasTranslator::new(mysql.alias_format());
+    let aux_params = [mysql.aux_params()];
+    let aux_params = ParameterMap::new(&aux_params);
+
+    let mut sql = {
+        let registry = &*mysql.registry()?;
+        let mut builder = SqlBuilder::new(&ty, registry);
+        let result = builder.build_count("", query.borrow(), true)?;
+        result
+            .to_sql_with_modifier_and_extra(&aux_params, &mut alias_translator, "", "")
+            .map_err(ToqlError::from)?
+    };
+    mysql.exclude_deleted(&mut sql.0)?;
+    Ok(sql)
+}
 
 fn load_count<T, B, C>(
     mysql: &mut MySql<C>,
@@ -87,44 +168,20 @@ where
         + TreeMerge<Row, ToqlMySqlError>,
     B: Borrow<Query<T>>,
     <T as toql::key::Keyed>::Key: FromRow<Row,ToqlMySqlError>,
-    C: GenericConnection,
+    C: Connection,
     
 {
+    let ty = <T as Mapped>::type_name();
     let page_count = if let Some(Page::Counted(_, _)) = page {
         let unpaged_count: u32 = {
-            toql::log_literal_sql!("SELECT FOUND_ROWS();");
-            let r = mysql.conn().query("SELECT FOUND_ROWS();")?;
-            r.into_iter().next().unwrap().unwrap().get(0).unwrap()
+            let sql = Sql(FOUND_ROWS_SQL.to_string(), Vec::new());
+            let rows = mysql.select_rows(&ty, StatementKind::Count, "", sql)?;
+            count_value(rows)?
         };
         let unfiltered_count: u32 = {
-            
-            let sql = {
-                let alias_format = mysql.alias_format();
-            let ty = <T as Mapped>::type_name();
-            let mut alias_translator = AliasTranslator::new(alias_format);
-            let aux_params = [mysql.aux_params()];
-            let aux_params = ParameterMap::new(&aux_params);
-
-            let registry= &*mysql.registry()?;
-            let mut builder = SqlBuilder::new(&ty, registry);
-            let result = builder.build_count("", query.borrow(), true)?;
-                result
-                .to_sql_with_modifier_and_extra(&aux_params, &mut alias_translator, "", "")
-                .map_err(ToqlError::from)?
-            };
-
-            log_sql!(&sql);
-            let Sql(sql_stmt, args) = sql;
-
-            let args = crate::sql_arg::values_from_ref(&args);
-            let query_results = mysql.conn.prep_exec(sql_stmt, args)?;
-            query_results
-                .into_iter()
-                .next()
-                .unwrap()
-                .unwrap()
-                .get(0)
-                .unwrap()
+            let sql = build_count_sql(mysql, query)?;
+            let rows = mysql.select_rows(&ty, StatementKind::Count, "", sql)?;
+            count_value(rows)?
         };
         Some((unpaged_count, unfiltered_count))
     } else {
@@ -132,21 +189,24 @@ where
     };
     Ok(page_count)
 }
-fn load_top<T, B, C>(
-    mysql: &mut MySql<C>,
+
+/// Returns the value of a count select.
+fn count_value<N: mysql::prelude::FromValue>(rows: Vec<Row>) -> Result<N> {
+    let row = rows.into_iter().next().ok_or(ToqlError::NotFound)?;
+    let value = row.0.get_opt(0).ok_or(ToqlError::NotFound)?;
+    Ok(value.map_err(mysql::error::Error::from)?)
+}
+
+/// Build root select with hints and page limit.
+fn build_top_sql<T, B, C>(
+    mysql: &MySql<C>,
     query: &B,
-    page: Option<Page>,
-) -> Result<(Vec<T>, HashSet<String>, Option<(u32, u32)>)>
+    page: Option<&Page>,
+) -> Result<(Sql, BuildResult)>
 where
-    T: Keyed
-        + Mapped
-        + FromRow<Row,ToqlMySqlError>
-        + TreePredicate
-        + TreeIndex<Row, ToqlMySqlError>
-        + TreeMerge<Row, ToqlMySqlError>,
+    T: Mapped,
     B: Borrow<Query<T>>,
-    <T as toql::key::Keyed>::Key: FromRow<Row,ToqlMySqlError>,
-    C: GenericConnection,
+    C: Connection,
 {
     use std::borrow::Cow;
 
@@ -161,7 +221,6 @@ where
         builder.build_select("", query.borrow())?
     };
 
-    let unmerged = result.unmerged_paths().clone();
     let mut alias_translator = AliasTranslator::new(alias_format);
     let aux_params = [mysql.aux_params()];
     let aux_params = ParameterMap::new(&aux_params);
@@ -175,33 +234,65 @@ where
         }
         None => Cow::Borrowed(""),
     };
+    let extra = match mysql.lock_mode {
+        LockMode::None => extra,
+        lock_mode if extra.is_empty() => Cow::Borrowed(lock_mode.to_sql()),
+        lock_mode => Cow::Owned(format!("{} {}", extra, lock_mode.to_sql())),
+    };
 
-    let modifier = if let Some(Page::Counted(_, _)) = page {
-        "SQL_CALC_FOUND_ROWS"
-    } else {
-        ""
+    let modifier = {
+        let mut modifier = mysql.select_hint("");
+        if let Some(Page::Counted(_, _)) = page {
+            if !modifier.is_empty() {
+                modifier.push(' ');
+            }
+            modifier.push_str("SQL_CALC_FOUND_ROWS");
+        }
+        modifier
     };
 
-    let sql = 
+    let mut sql = 
     {result
         .to_sql_with_modifier_and_extra(
             &aux_params,
             &mut alias_translator,
-            modifier,
+            &modifier,
             extra.borrow(),
         )
         .map_err(ToqlError::from)?
         };
+    if let Some(load_options) = &mysql.load_options {
+        load_options.apply_index_hints(&mut sql.0, &mut alias_translator);
+    }
+    mysql.exclude_deleted(&mut sql.0)?;
+    Ok((sql, result))
+}
+
+fn load_top<T, B, C>(
+    mysql: &mut MySql<C>,
+    query: &B,
+    page: Option<Page>,
+) -> Result<(Vec<T>, HashSet<String>, Option<(u32, u32)>)>
+where
+    T: Keyed
+        + Mapped
+        + FromRow<Row,ToqlMySqlError>
+        + TreePredicate
+        + TreeIndex<Row, ToqlMySqlError>
+        + TreeMerge<Row, ToqlMySqlError>,
+    B: Borrow<Query<T>>,
+    <T as toql::key::Keyed>::Key: FromRow<Row,ToqlMySqlError>,
+    C: Connection,
+{
+    let ty = <T as Mapped>::type_name();
 
-    log_sql!(&sql);
-    let Sql(sql_stmt, args) = sql;
+    let (sql, result) = build_top_sql(mysql, query, page.as_ref())?;
+    let unmerged = result.unmerged_paths().clone();
 
-    let args = crate::sql_arg::values_from_ref(&args);
-    let query_results = mysql.conn.prep_exec(sql_stmt, args)?;
+    let rows = mysql.select_rows(&ty, StatementKind::Select, "", sql)?;
 
     let mut entities: Vec<T> = Vec::new();
-    for r in query_results {
-        let r = Row(r?);
+    for r in rows {
         let mut iter = result.selection_stream().iter();
         let mut i = 0usize;
        if let Some(e) = <T as toql::from_row::FromRow<Row,ToqlMySqlError>>::from_row(&r, &mut i, &mut iter)? 
@@ -217,86 +308,86 @@ where
     Ok((entities, unmerged, page_count))
 }
 
-fn load_and_merge<T, B, C>(
-    mysql: &mut MySql<C>,
-    query: &B,
-    entities: &mut Vec<T>,
-    unmerged_paths: &HashSet<String>,
-) -> Result<HashSet<String>>
+/// Returns the canonical table alias of the root mapper.
+fn merge_base_alias<T, C>(mysql: &MySql<C>) -> Result<String>
 where
-    T: Keyed
-        + Mapped
-        + FromRow<Row,ToqlMySqlError>
-        + TreePredicate
-        + TreeIndex<Row, ToqlMySqlError>
-        + TreeMerge<Row, ToqlMySqlError>,
+    T: Mapped,
+    C: Connection,
+{
+    let ty = <T as Mapped>::type_name();
+    let registry = &*mysql.registry()?;
+    let mapper = registry
+        .mappers
+        .get(&ty)
+        .ok_or(ToqlError::MapperMissing(ty.clone()))?;
+    Ok(mapper.canonical_table_alias.clone())
+}
 
+/// Build merge select for a path.
+///
+/// The merge select is restricted to the keys of the given entities.
+/// Without entities the key predicate is omitted, this is used to explain a load.
+fn build_merge_sql<T, B, C>(
+    mysql: &MySql<C>,
+    query: &B,
+    merge_base_alias: &str,
+    root_path: &str,
+    entities: Option<&[T]>,
+) -> Result<(Sql, BuildResult)>
+where
+    T: Mapped + TreePredicate,
     B: Borrow<Query<T>>,
-    <T as toql::key::Keyed>::Key: FromRow<Row,ToqlMySqlError>,
-    C: GenericConnection,
+    C: Connection,
 {
     use toql::sql_expr::SqlExpr;
 
     let ty = <T as Mapped>::type_name();
-    let mut pending_paths = HashSet::new();
 
-   
-    let merge_base_alias = {
+    // Get merge JOIN with ON from mapper
+    let mut result = {
         let registry = &*mysql.registry()?;
-        let mapper = registry
-            .mappers
-            .get(&ty)
-            .ok_or(ToqlError::MapperMissing(ty.clone()))?;
-         mapper.canonical_table_alias.clone()
+        let mut builder = SqlBuilder::new(&ty, registry); // Add alias format or translator to constructor
+        builder.build_select(root_path, query.borrow())?
     };
 
-    for root_path in unmerged_paths {
-        // Get merge JOIN with ON from mapper
-       
-        let mut result = {
-            let registry = &*mysql.registry()?;
-            let mut builder = SqlBuilder::new(&ty, registry); // Add alias format or translator to constructor
-            builder.build_select(root_path.as_str(), query.borrow())?
-        };
-        pending_paths = result.unmerged_paths().clone();
-
-        let other_alias = result.table_alias().clone();
+    let other_alias = result.table_alias().clone();
 
-        // Build merge join
-        // Get merge join and custom on predicate from mapper
-        let on_sql_expr = {
-            let registry = &*mysql.registry()?;
-            let builder = SqlBuilder::new(&ty, registry); // Add alias format or translator to constructor
-            builder.merge_expr(&root_path)?
-        };
+    // Build merge join
+    // Get merge join and custom on predicate from mapper
+    let on_sql_expr = {
+        let registry = &*mysql.registry()?;
+        let builder = SqlBuilder::new(&ty, registry); // Add alias format or translator to constructor
+        builder.merge_expr(root_path)?
+    };
 
-        let (merge_join, merge_on) = {
-            let merge_resolver = Resolver::new()
-                .with_self_alias(&merge_base_alias)
-                .with_other_alias(&result.table_alias());
-            (
-                merge_resolver
-                    .resolve(&on_sql_expr.0)
-                    .map_err(ToqlError::from)?,
-                merge_resolver
-                    .resolve(&on_sql_expr.1)
-                    .map_err(ToqlError::from)?,
-            )
-        };
+    let (merge_join, merge_on) = {
+        let merge_resolver = Resolver::new()
+            .with_self_alias(merge_base_alias)
+            .with_other_alias(&result.table_alias());
+        (
+            merge_resolver
+                .resolve(&on_sql_expr.0)
+                .map_err(ToqlError::from)?,
+            merge_resolver
+                .resolve(&on_sql_expr.1)
+                .map_err(ToqlError::from)?,
+        )
+    };
 
-        //println!("{} ON {}", merge_join, merge_on);
-        result.push_join(merge_join);
-        result.push_join(SqlExpr::literal("ON ("));
-        result.push_join(merge_on);
+    //println!("{} ON {}", merge_join, merge_on);
+    result.push_join(merge_join);
+    result.push_join(SqlExpr::literal("ON ("));
+    result.push_join(merge_on);
 
-        // Get ON predicate from entity keys
+    // Get ON predicate from entity keys
+    if let Some(entities) = entities {
         let mut predicate_expr = SqlExpr::new();
-        let (_field, ancestor_path) = FieldPath::split_basename(root_path.as_str());
+        let (_field, ancestor_path) = FieldPath::split_basename(root_path);
         let ancestor_path = ancestor_path.unwrap_or(FieldPath::from(""));
         let mut d = ancestor_path.descendents();
 
-        let columns =
-            TreePredicate::columns(entities.get(0).unwrap(), &mut d).map_err(ToqlError::from)?;
+        let entity = entities.get(0).ok_or(ToqlError::NotFound)?;
+        let columns = TreePredicate::columns(entity, &mut d).map_err(ToqlError::from)?;
 
         let mut args = Vec::new();
         for e in entities.iter() {
@@ -310,7 +401,7 @@ where
 
         let predicate_expr = {
             let merge_resolver = Resolver::new()
-                .with_self_alias(&merge_base_alias)
+                .with_self_alias(merge_base_alias)
                 .with_other_alias(other_alias.as_str());
             merge_resolver
                 .resolve(&predicate_expr)
@@ -318,22 +409,70 @@ where
         };
         result.push_join(SqlExpr::literal(" AND "));
         result.push_join(predicate_expr);
-        result.push_join(SqlExpr::literal(")"));
+    }
+    result.push_join(SqlExpr::literal(")"));
 
-        // Build SQL query statement
+    // Build SQL query statement
 
-        let mut alias_translator = AliasTranslator::new(mysql.alias_format());
-        let aux_params = [mysql.aux_params()];
-        let aux_params = ParameterMap::new(&aux_params);
-        let Sql(sql, args) = result
-            .to_sql(&aux_params, &mut alias_translator)
-            .map_err(ToqlError::from)?;
-        dbg!(&sql);
-        dbg!(&args);
+    let mut alias_translator = AliasTranslator::new(mysql.alias_format());
+    let aux_params = [mysql.aux_params()];
+    let aux_params = ParameterMap::new(&aux_params);
+    let modifier = mysql.select_hint(root_path);
+    let mut sql = result
+        .to_sql_with_modifier_and_extra(
+            &aux_params,
+            &mut alias_translator,
+            &modifier,
+            mysql.lock_mode.to_sql(),
+        )
+        .map_err(ToqlError::from)?;
+    if let Some(load_options) = &mysql.load_options {
+        load_options.apply_index_hints(&mut sql.0, &mut alias_translator);
+    }
+    mysql.exclude_deleted(&mut sql.0)?;
+    Ok((sql, result))
+}
+
+fn load_and_merge<T, B, C>(
+    mysql: &mut MySql<C>,
+    query: &B,
+    entities: &mut Vec<T>,
+    unmerged_paths: &HashSet<String>,
+) -> Result<HashSet<String>>
+where
+    T: Keyed
+        + Mapped
+        + FromRow<Row,ToqlMySqlError>
+        + TreePredicate
+        + TreeIndex<Row, ToqlMySqlError>
+        + TreeMerge<Row, ToqlMySqlError>,
+
+    B: Borrow<Query<T>>,
+    <T as toql::key::Keyed>::Key: FromRow<Row,ToqlMySqlError>,
+    C: Connection,
+{
+    let ty = <T as Mapped>::type_name();
+    let mut pending_paths = HashSet::new();
+
+    // Nothing to merge into, skip merge selects
+    if entities.is_empty() {
+        return Ok(pending_paths);
+    }
+   
+    let merge_base_alias = merge_base_alias::<T, _>(mysql)?;
+
+    for root_path in unmerged_paths {
+        let (sql, result) = build_merge_sql(
+            mysql,
+            query,
+            &merge_base_alias,
+            root_path,
+            Some(entities.as_slice()),
+        )?;
+        pending_paths = result.unmerged_paths().clone();
 
         // Load from database
-        let args = crate::sql_arg::values_from_ref(&args);
-        let query_results = mysql.conn.prep_exec(sql, args)?;
+        let rows = mysql.select_rows(&ty, StatementKind::Merge, root_path, sql)?;
 
         // Build index
         let mut index: HashMap<u64, Vec<usize>> = HashMap::new();
@@ -344,11 +483,6 @@ where
 
         // TODO Batch process rows
         // TODO Introduce traits that do not need copy to vec
-        let mut rows = Vec::with_capacity(100);
-
-        for q in query_results {
-            rows.push(Row(q?)); // Stream into Vec
-        }
 
         let row_offset = 0; // key must be forst columns in reow
         <T as TreeIndex<Row, ToqlMySqlError>>::index(&mut d, field, &rows, row_offset, &mut index)?;
@@ -369,7 +503,22 @@ where
     Ok(pending_paths)
 }
 
-fn load<T, B, C>(
+/// Map the root type and its dependencies, if not done yet.
+fn register_root<T, C>(mysql: &MySql<C>) -> Result<()>
+where
+    T: Mapped + TreeMap,
+    C: Connection,
+{
+    let type_name = <T as Mapped>::type_name();
+    if !mysql.cache.registered_roots.read().map_err(ToqlError::from)?.contains(&type_name) {
+        let mut cache = &mut *mysql.cache.registry.write().map_err(ToqlError::from)?;
+        <T as TreeMap>::map(&mut cache)?;
+        mysql.cache.registered_roots.write().map_err(ToqlError::from)?.insert(type_name);
+    }
+    Ok(())
+}
+
+fn load_from_db<T, B, C>(
     mysql: &mut MySql<C>,
     query: B,
     page: Option<Page>,
@@ -384,17 +533,24 @@ where
         + TreeMerge<Row, ToqlMySqlError>,
     B: Borrow<Query<T>>,
     <T as toql::key::Keyed>::Key: FromRow<Row,ToqlMySqlError>,
-    C: GenericConnection,
+    C: Connection,
 {
     let type_name = <T as Mapped>::type_name();
-    if !mysql.cache.registered_roots.read().map_err(ToqlError::from)?.contains(&type_name) {
-        let mut cache = &mut *mysql.cache.registry.write().map_err(ToqlError::from)?;
-        <T as TreeMap>::map(&mut cache)?;
-        mysql.cache.registered_roots.write().map_err(ToqlError::from)?.insert(type_name);
+    let operation = Operation::new("load", &type_name);
+    register_root::<T, C>(mysql)?;
+
+    if let Some(load_options) = &mysql.load_options {
+        load_options.validate(&*mysql.registry()?, &<T as Mapped>::type_name())?;
     }
 
     let (mut entities, mut unmerged_paths, counts) = load_top(mysql, &query, page)?;
 
+    // Root returned no rows, merge paths can be skipped
+    if entities.is_empty() {
+        operation.rows(0);
+        return Ok((entities, counts));
+    }
+
     loop {
         let mut pending_paths = load_and_merge(mysql, &query, &mut entities, &unmerged_paths)?;
 
@@ -407,35 +563,180 @@ where
         unmerged_paths.extend(pending_paths.drain());
     }
 
+    operation.rows(entities.len() as u64);
     Ok((entities, counts))
 }
 
-fn execute_update_delete_sql<C>(statement: Sql, conn: &mut C) -> Result<u64>
+fn explain_load<T, C>(
+    mysql: &mut MySql<C>,
+    query: &Query<T>,
+    page: Option<&Page>,
+    with_plans: bool,
+) -> Result<Vec<ExplainedStatement>>
 where
-    C: GenericConnection,
+    T: Mapped + TreeMap + TreePredicate,
+    C: Connection,
 {
-    log_sql!(&statement);
-    let Sql(update_stmt, params) = statement;
+    register_root::<T, C>(mysql)?;
+
+    if let Some(load_options) = &mysql.load_options {
+        load_options.validate(&*mysql.registry()?, &<T as Mapped>::type_name())?;
+    }
+    let merge_base_alias = merge_base_alias::<T, _>(mysql)?;
 
-    let mut stmt = conn.prepare(&update_stmt)?;
-    let res = stmt.execute(values_from(params))?;
-    Ok(res.affected_rows())
+    let mut statements = Vec::new();
+
+    // Root select
+    let (sql, result) = build_top_sql(mysql, &query, page)?;
+    statements.push((StatementKind::Select, String::new(), sql));
+
+    // Count selects
+    if let Some(Page::Counted(_, _)) = page {
+        let sql = Sql(FOUND_ROWS_SQL.to_string(), Vec::new());
+        statements.push((StatementKind::Count, String::new(), sql));
+        let sql = build_count_sql(mysql, &query)?;
+        statements.push((StatementKind::Count, String::new(), sql));
+    }
+
+    // Merge selects, paths in the order they would be merged
+    let mut merged: HashSet<String> = HashSet::new();
+    let mut unmerged_paths = result.unmerged_paths().iter().cloned().collect::<Vec<_>>();
+    unmerged_paths.sort();
+    while !unmerged_paths.is_empty() {
+        let mut pending_paths = Vec::new();
+        for root_path in &unmerged_paths {
+            let (sql, result) =
+                build_merge_sql::<T, _, _>(mysql, &query, &merge_base_alias, root_path, None)?;
+            statements.push((StatementKind::Merge, root_path.to_owned(), sql));
+            merged.insert(root_path.to_owned());
+            pending_paths.extend(result.unmerged_paths().iter().cloned());
+        }
+        pending_paths.retain(|p| !merged.contains(p));
+        pending_paths.sort();
+        pending_paths.dedup();
+        unmerged_paths = pending_paths;
+    }
+
+    let mut explained = Vec::with_capacity(statements.len());
+    for (kind, path, sql) in statements {
+        let plan = if with_plans && sql.0 != FOUND_ROWS_SQL {
+            mysql.explain_plan(&sql.0, &sql.1)?
+        } else {
+            None
+        };
+        explained.push(ExplainedStatement {
+            kind,
+            path,
+            sql,
+            plan,
+        });
+    }
+    Ok(explained)
 }
 
-fn execute_insert_sql<C>(statement: Sql, conn: &mut C) -> Result<u64>
+/// Load from the result cache or the database.
+///
+/// The result cache keeps the rows of each select, cache hits build the entities from these rows without statements.
+fn load<T, B, C>(
+    mysql: &mut MySql<C>,
+    query: B,
+    page: Option<Page>,
+) -> Result<(Vec<T>, Option<(u32, u32)>)>
 where
-    C: GenericConnection,
+    T: Keyed
+        + TreeMap
+        + Mapped
+        + FromRow<Row,ToqlMySqlError>
+        + TreePredicate
+        + TreeIndex<Row, ToqlMySqlError>
+        + TreeMerge<Row, ToqlMySqlError>,
+    B: Borrow<Query<T>>,
+    <T as toql::key::Keyed>::Key: FromRow<Row,ToqlMySqlError>,
+    C: Connection,
 {
-    log_sql!(&statement);
-    let Sql(insert_stmt, params) = statement;
+    // Locking reads and reads within a transaction must see the current rows
+    let result_cache = match mysql.result_cache {
+        Some(c) if mysql.lock_mode == LockMode::None && !mysql.in_transaction() => c,
+        _ => return load_from_db(mysql, query, page),
+    };
+
+    // The built statements contain the aux params of the query, hints and the filter of deleted rows
+    let statements = explain_load(mysql, query.borrow(), page.as_ref(), false)?
+        .into_iter()
+        .map(|s| (s.sql.0, s.sql.1))
+        .collect::<Vec<_>>();
+    let options = format!(
+        "{:?}",
+        (
+            mysql.load_options.as_ref().map(LoadOptions::cache_key),
+            mysql.include_deleted,
+            mysql.reads_primary(),
+            statements
+        )
+    );
+    let type_name = <T as Mapped>::type_name();
+    let key = ResultCache::key(
+        &type_name,
+        &query.borrow().to_string(),
+        page.as_ref(),
+        mysql.roles(),
+        mysql.aux_params(),
+        &options,
+    );
+
+    mysql.cached_load = Some(match result_cache.get::<CachedRows>(&key)? {
+        Some(rows) => CachedLoad::Replay(rows),
+        None => CachedLoad::Record {
+            tables: HashSet::new(),
+            rows: CachedRows::new(),
+        },
+    });
+    let loaded = load_from_db(mysql, query, page);
+    let cached_load = mysql.cached_load.take();
+    let loaded = loaded?;
+    if let Some(CachedLoad::Record { tables, rows }) = cached_load {
+        result_cache.insert(tables, key, rows)?;
+    }
+    Ok(loaded)
+}
 
-    let mut stmt = conn.prepare(&insert_stmt)?;
-    let res = stmt.execute(values_from(params))?;
-    Ok(res.last_insert_id())
+/// Rows of the selects of a load, keyed by statement and arguments.
+type CachedRows = HashMap<String, VecDeque<Vec<mysql::Row>>>;
+
+/// Rows of a load with result cache.
+enum CachedLoad {
+    /// Cache miss, the rows and the tables of all selects are recorded for the cache.
+    Record {
+        tables: HashSet<String>,
+        rows: CachedRows,
+    },
+    /// Cache hit, selects return the cached rows.
+    Replay(CachedRows),
 }
 
-pub struct MySql<'a, C: GenericConnection> {
+pub struct MySql<'a, C: Connection> {
     conn: &'a mut C,
+    replica: Option<&'a mut C>,
+    read_preference: ReadPreference,
+    written: bool,
+    max_execution_time: Option<Duration>,
+    load_options: Option<LoadOptions>,
+    lock_mode: LockMode,
+    require_updated_rows: bool,
+    merge_mode: MergeMode,
+    version_columns: Option<VersionColumns>,
+    soft_delete: Option<SoftDelete>,
+    include_deleted: bool,
+    audit_trail: Option<AuditTrail>,
+    auto_stamps: Option<AutoStamps>,
+    result_cache: Option<&'a ResultCache>,
+    cached_load: Option<CachedLoad>,
+    written_tables: HashSet<String>,
+    transaction_depth: usize,
+    log_args: bool,
+    metrics: Option<&'a dyn MetricsSink>,
+    slow_query_log: Option<SlowQueryLog>,
+    dry_run: Option<DryRun>,
     context : Context,
     cache: &'a Cache
    /*  roles: HashSet<String>,
@@ -444,120 +745,869 @@ pub struct MySql<'a, C: GenericConnection> {
     alias_format: AliasFormat, */
 }
 
-impl<'a, C: 'a + GenericConnection> MySql<'a, C> {
+impl<'a, C: 'a + Connection> MySql<'a, C> {
     /// Create connection wrapper from MySql connection or transaction.
     ///
     /// Use the connection wrapper to access all Toql functionality.
+    /// To configure roles, aux params, alias format and options use [builder](#method.builder).
     pub fn from(conn: &'a mut C, cache: &'a Cache) -> MySql<'a, C> {
-        Self::with_roles_and_aux_params(conn, cache, HashSet::new(), HashMap::new())
+        Self::with_context(
+            conn,
+            cache,
+            Context {
+                roles: HashSet::new(),
+                aux_params: HashMap::new(),
+                alias_format: AliasFormat::Canonical,
+            },
+        )
+    }
+
+    /// Returns a builder to configure the connection wrapper.
+    pub fn builder(conn: &'a mut C, cache: &'a Cache) -> MySqlBuilder<'a, C> {
+        MySqlBuilder::new(conn, cache)
     }
 
     /// Create connection wrapper from MySql connection or transaction and roles.
     ///
     /// Use the connection wrapper to access all Toql functionality.
+    #[deprecated(note = "Use `MySql::builder(conn, cache).with_roles(roles).build()`")]
     pub fn with_roles(
         conn: &'a mut C,
-         cache: &'a Cache,
+        cache: &'a Cache,
         roles: HashSet<String>,
     ) -> MySql<'a, C> {
-        Self::with_roles_and_aux_params(conn, cache, roles, HashMap::new())
+        Self::builder(conn, cache).with_roles(roles).build()
     }
-    /// Create connection wrapper from MySql connection or transaction and roles.
+    /// Create connection wrapper from MySql connection or transaction and aux params.
     ///
     /// Use the connection wrapper to access all Toql functionality.
+    #[deprecated(note = "Use `MySql::builder(conn, cache).with_aux_params(aux_params).build()`")]
     pub fn with_aux_params(
         conn: &'a mut C,
-        cache : &'a Cache,
+        cache: &'a Cache,
         aux_params: HashMap<String, SqlArg>,
     ) -> MySql<'a, C> {
-        Self::with_roles_and_aux_params(conn, cache, HashSet::new(), aux_params)
+        Self::builder(conn, cache).with_aux_params(aux_params).build()
     }
-    /// Create connection wrapper from MySql connection or transaction and roles.
+    /// Create connection wrapper from MySql connection or transaction, roles and aux params.
     ///
     /// Use the connection wrapper to access all Toql functionality.
+    #[deprecated(note = "Use `MySql::builder(conn, cache)` with roles and aux params")]
     pub fn with_roles_and_aux_params(
         conn: &'a mut C,
         cache: &'a Cache,
         roles: HashSet<String>,
         aux_params: HashMap<String, SqlArg>,
     ) -> MySql<'a, C> {
+        Self::builder(conn, cache)
+            .with_roles(roles)
+            .with_aux_params(aux_params)
+            .build()
+    }
+
+    /// Create connection wrapper from MySql connection or transaction and a context.
+    ///
+    /// Use the connection wrapper to access all Toql functionality.
+    pub fn with_context(conn: &'a mut C, cache: &'a Cache, context: Context) -> MySql<'a, C> {
         MySql {
             conn,
+            replica: None,
+            read_preference: ReadPreference::default(),
+            written: false,
+            max_execution_time: None,
+            load_options: None,
+            lock_mode: LockMode::default(),
+            require_updated_rows: false,
+            merge_mode: MergeMode::default(),
+            version_columns: None,
+            soft_delete: None,
+            include_deleted: false,
+            audit_trail: None,
+            auto_stamps: None,
+            result_cache: None,
+            cached_load: None,
+            written_tables: HashSet::new(),
+            transaction_depth: 0,
+            log_args: false,
+            metrics: None,
+            slow_query_log: None,
+            dry_run: None,
             cache,
-            context: Context {
-                roles,
-                aux_params,
-                alias_format: AliasFormat::Canonical,
-            }
+            context,
         }
     }
 
-    /// Set roles
+    /// Set replica connection
     ///
-    /// After setting the roles all Toql functions are validated against these roles.
-    /// Roles on fields can be used to restrict the access (Only super admin can see this field, only group admin can update this field),
-    pub fn set_roles(&mut self, roles: HashSet<String>) -> &mut Self {
-        self.context.roles = roles;
+    /// Loads and counts are served from the replica according to the read preference.
+    /// Inserts, updates and deletes always use the primary connection.
+    pub fn set_replica(&mut self, replica: &'a mut C) -> &mut Self {
+        self.replica = Some(replica);
         self
     }
 
-    pub fn conn(&mut self) -> &'_ mut C {
-        self.conn
+    /// Set read preference
+    ///
+    /// Use `ReadPreference::PrimaryAfterWrite` to read your own writes.
+    pub fn set_read_preference(&mut self, read_preference: ReadPreference) -> &mut Self {
+        self.read_preference = read_preference;
+        self
     }
 
-    pub fn registry(&self) -> std::result::Result<RwLockReadGuard<'_, SqlMapperRegistry>,ToqlError> {
-        self.cache.registry.read().map_err(ToqlError::from)
-    }
-    pub fn roles(&self) -> &HashSet<String> {
-        &self.context.roles
+    pub fn read_preference(&self) -> ReadPreference {
+        self.read_preference
     }
 
-    pub fn alias_format(&self) -> AliasFormat {
-        self.context.alias_format.to_owned()
+    /// Set maximum execution time for selects
+    ///
+    /// Root and merge selects of loads are interrupted by the server after this time
+    /// and fail with `ToqlMySqlError::QueryTimeout`.
+    /// The time is rounded up to whole milliseconds.
+    pub fn set_max_execution_time(&mut self, max_execution_time: Option<Duration>) -> &mut Self {
+        self.max_execution_time = max_execution_time;
+        self
     }
 
-    /* pub fn set_aux_params(&mut self, aux_params: HashMap<String, SqlArg>) -> &mut Self {
-           self.aux_params = aux_params;
-           self
-       }
-    */
-    pub fn aux_params(&self) -> &HashMap<String, SqlArg> {
-        &self.context.aux_params
+    pub fn max_execution_time(&self) -> Option<Duration> {
+        self.max_execution_time
     }
 
-    /// Insert one struct.
+    /// Run function with a different maximum execution time for selects.
     ///
-    /// Skip fields in struct that are auto generated with `#[toql(skip_inup)]`.
-    /// Returns the last generated id.
-    pub fn insert_many<T, Q>(&mut self, paths: Paths<T>, mut entities: &mut [Q]) -> Result<u64>
+    /// The previous maximum execution time is restored afterwards.
+    pub fn with_max_execution_time<F, R>(&mut self, max_execution_time: Duration, f: F) -> Result<R>
     where
-        T: TreeInsert + Mapped + TreeIdentity,
-        Q: BorrowMut<T>,
+        F: FnOnce(&mut Self) -> Result<R>,
     {
-        use toql::tree::tree_identity::IdentityAction;
-        // Build up execution tree
-        // Path `a_b_merge1_c_d_merge2_e` becomes
-        // [0] = [a, c, e]
-        // [1] = [a_b, c_d]
-        // [m] = [merge1, merge2]
-        // Then execution order is [1], [0], [m]
-
-        // TODO should be possible to impl with &str
-        let mut joins: Vec<HashSet<String>> = Vec::new();
-        let mut merges: HashSet<String> = HashSet::new();
+        let previous = self.max_execution_time.replace(max_execution_time);
+        let result = f(self);
+        self.max_execution_time = previous;
+        result
+    }
 
-        toql::backend::insert::plan_insert_order::<T, _>(
-            &self.registry()?.mappers,
-            &paths.list,
-            &mut joins,
-            &mut merges,
-        )?;
+    /// Set optimizer and index hints for loads
+    ///
+    /// The hints are validated against the mapper of the loaded struct before any select is run.
+    pub fn set_load_options(&mut self, load_options: Option<LoadOptions>) -> &mut Self {
+        self.load_options = load_options;
+        self
+    }
 
-        // Insert root
-        let sql = {
-            let aux_params = [self.aux_params()];
-            let aux_params = ParameterMap::new(&aux_params);
-            let home_path = FieldPath::default();
+    pub fn load_options(&self) -> Option<&LoadOptions> {
+        self.load_options.as_ref()
+    }
+
+    /// Set locking read for root and merge selects of loads.
+    ///
+    /// While a lock mode is set, loads and counts read from the primary connection.
+    pub fn set_lock_mode(&mut self, lock_mode: LockMode) -> &mut Self {
+        self.lock_mode = lock_mode;
+        self
+    }
+
+    pub fn lock_mode(&self) -> LockMode {
+        self.lock_mode
+    }
+
+    /// Fail updates of entities that affect no rows with `NoRowsUpdated`.
+    ///
+    /// MySQL only counts changed rows, so an update that affects no rows
+    /// is checked with a select on the primary connection.
+    /// Unchanged entities pass, vanished entities fail.
+    pub fn set_require_updated_rows(&mut self, require_updated_rows: bool) -> &mut Self {
+        self.require_updated_rows = require_updated_rows;
+        self
+    }
+
+    pub fn require_updated_rows(&self) -> bool {
+        self.require_updated_rows
+    }
+
+    /// Set how `update_many` writes merged entities.
+    pub fn set_merge_mode(&mut self, merge_mode: MergeMode) -> &mut Self {
+        self.merge_mode = merge_mode;
+        self
+    }
+
+    pub fn merge_mode(&self) -> MergeMode {
+        self.merge_mode
+    }
+
+    /// Set version fields by mapper for optimistic concurrency control of versioned updates.
+    pub fn set_version_columns(&mut self, version_columns: Option<VersionColumns>) -> &mut Self {
+        self.version_columns = version_columns;
+        self
+    }
+
+    pub fn version_columns(&self) -> Option<&VersionColumns> {
+        self.version_columns.as_ref()
+    }
+
+    /// Set soft delete columns
+    ///
+    /// Deletes of the tables of these mappers set the deletion timestamp and loads skip deleted rows.
+    pub fn set_soft_delete(&mut self, soft_delete: Option<SoftDelete>) -> &mut Self {
+        self.soft_delete = soft_delete;
+        self
+    }
+
+    pub fn soft_delete(&self) -> Option<&SoftDelete> {
+        self.soft_delete.as_ref()
+    }
+
+    /// Include soft deleted rows in loads and counts.
+    pub fn set_include_deleted(&mut self, include_deleted: bool) -> &mut Self {
+        self.include_deleted = include_deleted;
+        self
+    }
+
+    pub fn include_deleted(&self) -> bool {
+        self.include_deleted
+    }
+
+    /// Set audit trail
+    ///
+    /// Inserts, updates and deletes write audit rows into the audit table in the same transaction.
+    pub fn set_audit_trail(&mut self, audit_trail: Option<AuditTrail>) -> &mut Self {
+        self.audit_trail = audit_trail;
+        self
+    }
+
+    pub fn audit_trail(&self) -> Option<&AuditTrail> {
+        self.audit_trail.as_ref()
+    }
+
+    /// Set automatic timestamps and user stamps of inserts and updates.
+    pub fn set_auto_stamps(&mut self, auto_stamps: Option<AutoStamps>) -> &mut Self {
+        self.auto_stamps = auto_stamps;
+        self
+    }
+
+    pub fn auto_stamps(&self) -> Option<&AutoStamps> {
+        self.auto_stamps.as_ref()
+    }
+
+    /// Run function with soft deleted rows included in loads and counts.
+    ///
+    /// The previous setting is restored afterwards.
+    pub fn with_deleted<F, R>(&mut self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut Self) -> Result<R>,
+    {
+        let previous = std::mem::replace(&mut self.include_deleted, true);
+        let result = f(self);
+        self.include_deleted = previous;
+        result
+    }
+
+    /// Run function with different load options.
+    ///
+    /// The previous load options are restored afterwards.
+    pub fn with_load_options<F, R>(&mut self, load_options: LoadOptions, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut Self) -> Result<R>,
+    {
+        let previous = self.load_options.replace(load_options);
+        let result = f(self);
+        self.load_options = previous;
+        result
+    }
+
+    /// Set result cache
+    ///
+    /// Loads without lock mode and outside of transactions are served from the result cache.
+    /// Successful inserts, updates and deletes invalidate the cached results that read a written table.
+    pub fn set_result_cache(&mut self, result_cache: Option<&'a ResultCache>) -> &mut Self {
+        self.result_cache = result_cache;
+        self
+    }
+
+    pub fn result_cache(&self) -> Option<&'a ResultCache> {
+        self.result_cache
+    }
+
+    /// Enable logging of argument values
+    ///
+    /// Argument values may contain personal data and are redacted by default.
+    pub fn set_log_args(&mut self, log_args: bool) -> &mut Self {
+        self.log_args = log_args;
+        self
+    }
+
+    /// Set metrics sink
+    ///
+    /// The metrics sink is invoked after every statement.
+    pub fn set_metrics(&mut self, metrics: Option<&'a dyn MetricsSink>) -> &mut Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Set slow query log
+    ///
+    /// Root and merge selects that exceed the threshold are logged, optionally with their query plan.
+    pub fn set_slow_query_log(&mut self, slow_query_log: Option<SlowQueryLog>) -> &mut Self {
+        self.slow_query_log = slow_query_log;
+        self
+    }
+
+    /// Enable dry run for mutations
+    ///
+    /// Inserts, updates and deletes are recorded instead of executed, loads and counts run as usual.
+    /// Disabling dry run discards all recorded statements.
+    pub fn set_dry_run(&mut self, dry_run: bool) -> &mut Self {
+        self.dry_run = if dry_run { Some(DryRun::new()) } else { None };
+        self
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run.is_some()
+    }
+
+    /// Returns the statements recorded in dry run mode in execution order and clears them.
+    pub fn take_recorded_statements(&mut self) -> Vec<RecordedStatement> {
+        match &mut self.dry_run {
+            Some(dry_run) => std::mem::take(&mut dry_run.statements),
+            None => Vec::new(),
+        }
+    }
+
+    /// Run select on the read connection and return all rows.
+    fn select_rows(
+        &mut self,
+        type_name: &str,
+        kind: StatementKind,
+        path: &str,
+        sql: Sql,
+    ) -> Result<Vec<Row>> {
+        let cache_key = self.cached_load.as_ref().map(|_| format!("{:?}", (&sql.0, &sql.1)));
+        if let (Some(CachedLoad::Replay(cached_rows)), Some(cache_key)) = (&mut self.cached_load, &cache_key) {
+            if let Some(rows) = cached_rows.get_mut(cache_key).and_then(VecDeque::pop_front) {
+                return Ok(rows.into_iter().map(Row).collect());
+            }
+        }
+
+        instrument::statement(kind, path, &sql.0, &sql.1, self.log_args);
+        let Sql(sql_stmt, args) = sql;
+
+        let start = Instant::now();
+        let result = self.read_conn().select(&sql_stmt, values_from_ref(&args));
+        if let Ok(rows) = &result {
+            self.observe(type_name, kind, path, &sql_stmt, start, rows.len() as u64);
+            if let (Some(CachedLoad::Record { tables, rows: cached_rows }), Some(cache_key)) =
+                (&mut self.cached_load, cache_key)
+            {
+                tables.extend(result_cache::statement_tables(&sql_stmt));
+                cached_rows.entry(cache_key).or_insert_with(VecDeque::new).push_back(rows.clone());
+            }
+        }
+
+        // Failed selects are logged too, they may have been interrupted by the execution time limit
+        if let Some(slow_query_log) = self.slow_query_log {
+            let duration = start.elapsed();
+            if slow_query_log.is_slow(kind, duration) {
+                let plan = if slow_query_log.should_explain(&sql_stmt) {
+                    // Plan is only diagnostic, failures must not fail the load
+                    self.explain_plan(&sql_stmt, &args).unwrap_or_else(|e| {
+                        log::warn!("Unable to explain slow statement: {:?}", e);
+                        None
+                    })
+                } else {
+                    None
+                };
+                slow_query::log(kind, path, &sql_stmt, &args, self.log_args, duration, plan.as_deref());
+            }
+        }
+        Ok(result?.into_iter().map(Row).collect())
+    }
+
+    /// Select rows on the primary connection, used for reads that lock rows.
+    fn select_primary(
+        &mut self,
+        type_name: &str,
+        kind: StatementKind,
+        path: &str,
+        sql: Sql,
+    ) -> Result<Vec<Row>> {
+        instrument::statement(kind, path, &sql.0, &sql.1, self.log_args);
+        let Sql(sql_stmt, args) = sql;
+
+        let start = Instant::now();
+        let rows = self.conn.select(&sql_stmt, values_from_ref(&args))?;
+        self.observe(type_name, kind, path, &sql_stmt, start, rows.len() as u64);
+        Ok(rows.into_iter().map(Row).collect())
+    }
+
+    /// Returns the query plan in JSON format from the read connection.
+    fn explain_plan(&mut self, sql: &str, args: &[SqlArg]) -> Result<Option<String>> {
+        let explain = format!("EXPLAIN FORMAT=JSON {}", sql);
+        let rows = self
+            .read_conn()
+            .select(&explain, values_from_ref(args))?;
+        Ok(rows.into_iter().next().and_then(|row| row.get::<String, _>(0)))
+    }
+
+    /// Execute insert, update or delete on the primary connection.
+    ///
+    /// Returns the number of affected rows and the last insert id.
+    fn execute_sql(
+        &mut self,
+        type_name: &str,
+        kind: StatementKind,
+        path: &str,
+        sql: Sql,
+    ) -> Result<(u64, u64)> {
+        instrument::statement(kind, path, &sql.0, &sql.1, self.log_args);
+        if let Some(dry_run) = &mut self.dry_run {
+            return Ok(dry_run.record(kind, path, sql));
+        }
+        let Sql(sql_stmt, args) = sql;
+
+        let start = Instant::now();
+        let (affected_rows, last_insert_id) = self.conn.execute(&sql_stmt, values_from(args))?;
+        self.observe(type_name, kind, path, &sql_stmt, start, affected_rows);
+        self.written = true;
+        self.invalidate_result_cache(&sql_stmt)?;
+        Ok((affected_rows, last_insert_id))
+    }
+
+    /// Run transaction control statement on the primary connection. Skipped in dry run mode.
+    fn query_drop(&mut self, sql: &str) -> Result<()> {
+        log::debug!("SQL `{}`", sql);
+        if self.dry_run.is_some() {
+            return Ok(());
+        }
+        self.conn.query_drop(sql)
+    }
+
+    /// Pass statement metrics to metrics sink.
+    fn observe(
+        &self,
+        type_name: &str,
+        kind: StatementKind,
+        path: &str,
+        sql: &str,
+        start: Instant,
+        rows: u64,
+    ) {
+        if let Some(sink) = self.metrics {
+            let fingerprint = metrics::fingerprint(sql);
+            sink.record(&StatementMetrics {
+                type_name,
+                kind,
+                path,
+                fingerprint: &fingerprint,
+                duration: start.elapsed(),
+                rows,
+            });
+        }
+    }
+
+    /// Write audit rows of a statement, if an audit trail is set and the statement affected rows.
+    ///
+    /// Metrics record the audit insert under the name of the audit table.
+    fn audit<F>(&mut self, affected_rows: u64, entries: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<Vec<AuditEntry>>,
+    {
+        if affected_rows == 0 {
+            return Ok(());
+        }
+        let (table, sql) = match &self.audit_trail {
+            Some(audit_trail) => (
+                audit_trail.table().to_owned(),
+                audit_trail.insert_sql(entries()?, &self.context.aux_params, &self.context.roles),
+            ),
+            None => return Ok(()),
+        };
+        if let Some(sql) = sql {
+            self.execute_sql(&table, StatementKind::Insert, "", sql)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the keys of the entities at a path in insert order, if an audit trail is set.
+    fn audit_keys<T, Q>(&self, path: &FieldPath, entities: &[Q]) -> Result<Vec<Vec<SqlArg>>>
+    where
+        T: TreePredicate,
+        Q: Borrow<T>,
+    {
+        let entity = match entities.get(0) {
+            Some(e) if self.audit_trail.is_some() => e.borrow(),
+            _ => return Ok(Vec::new()),
+        };
+        let columns = <T as TreePredicate>::columns(entity, &mut path.descendents())?;
+        let mut args = Vec::new();
+        for e in entities {
+            <T as TreePredicate>::args(e.borrow(), &mut path.descendents(), &mut args)?;
+        }
+        Ok(args
+            .chunks(columns.len().max(1))
+            .map(|k| k.to_vec())
+            .collect())
+    }
+
+    /// Returns the audit rows of the rows that a delete removes, if an audit trail is set.
+    ///
+    /// The rows are read and locked before the delete, their key is taken from the primary key columns.
+    fn deleted_entries(&mut self, type_name: &str, path: &str, sql: &Sql) -> Result<Vec<AuditEntry>> {
+        if self.audit_trail.is_none() {
+            return Ok(Vec::new());
+        }
+        let rows = self.deleted_rows(type_name, path, sql)?;
+        Ok(self.audit_deleted(type_name, path, &rows))
+    }
+
+    /// Returns the audit rows of deleted rows, if an audit trail is set.
+    fn audit_deleted(
+        &self,
+        type_name: &str,
+        path: &str,
+        rows: &[Vec<(String, bool, SqlArg)>],
+    ) -> Vec<AuditEntry> {
+        if self.audit_trail.is_none() {
+            return Vec::new();
+        }
+        rows.iter()
+            .map(|row| {
+                let key = row
+                    .iter()
+                    .filter(|(_, key, _)| *key)
+                    .map(|(_, _, v)| v.to_owned())
+                    .collect::<Vec<_>>();
+                let values = row
+                    .iter()
+                    .map(|(c, _, v)| (c.to_owned(), v.to_owned()))
+                    .collect::<Vec<_>>();
+                AuditEntry::deleted(type_name, path, &key, &values)
+            })
+            .collect()
+    }
+
+    /// Read and lock the rows that a delete removes, rows that are already soft deleted are skipped.
+    /// Dry runs take no locks and read no rows.
+    ///
+    /// Returns the name, the primary key flag and the value of the columns of every row.
+    fn deleted_rows(
+        &mut self,
+        type_name: &str,
+        path: &str,
+        sql: &Sql,
+    ) -> Result<Vec<Vec<(String, bool, SqlArg)>>> {
+        if self.dry_run.is_some() {
+            return Ok(Vec::new());
+        }
+        let mut select = match diff::DeleteStatement::parse(&sql.0) {
+            Some(delete) => format!("SELECT {}.* FROM {} FOR UPDATE", delete.alias, delete.from),
+            None => return Err(ToqlMySqlError::UnexpectedStatement(sql.0.to_owned())),
+        };
+        // Soft deletes skip rows that are already deleted
+        if let Some(deleted_columns) = self.deleted_columns()? {
+            deleted_columns.exclude_deleted(&mut select);
+        }
+        let rows = self.select_primary(type_name, StatementKind::Select, path, Sql(select, sql.1.clone()))?;
+        Ok(rows
+            .into_iter()
+            .map(|r| {
+                r.0.columns_ref()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, column)| {
+                        (
+                            column.name_str().to_string(),
+                            column.flags().contains(ColumnFlags::PRI_KEY_FLAG),
+                            arg_from(r.0.get::<mysql::Value, _>(i).unwrap_or(mysql::Value::NULL)),
+                        )
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    /// Add stamp columns to an insert, if auto stamps are set.
+    fn stamp_insert(&self, sql: Sql) -> Result<Sql> {
+        match &self.auto_stamps {
+            Some(stamps) => stamps.stamp_insert(sql, self.stamp_user(stamps)),
+            None => Ok(sql),
+        }
+    }
+
+    /// Add update stamp columns to an update, if auto stamps are set.
+    fn stamp_update(&self, sql: Sql) -> Result<Sql> {
+        match &self.auto_stamps {
+            Some(stamps) => stamps.stamp_update(sql, self.stamp_user(stamps)),
+            None => Ok(sql),
+        }
+    }
+
+    fn stamp_user(&self, stamps: &AutoStamps) -> Option<&SqlArg> {
+        stamps
+            .user_param()
+            .and_then(|p| self.context.aux_params.get(p))
+    }
+
+    /// Returns the deletion timestamp columns by the tables of the registered mappers, if soft delete is set.
+    fn deleted_columns(&self) -> Result<Option<DeletedColumns>> {
+        match &self.soft_delete {
+            Some(soft_delete) => Ok(Some(soft_delete.tables(&*self.registry()?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Exclude soft deleted rows from a select, unless they are included.
+    fn exclude_deleted(&self, sql: &mut String) -> Result<()> {
+        if !self.include_deleted {
+            if let Some(deleted_columns) = self.deleted_columns()? {
+                deleted_columns.exclude_deleted(sql);
+            }
+        }
+        Ok(())
+    }
+
+    /// Turn a delete into a soft delete, if soft delete is set.
+    fn soft_delete_sql(&self, sql: Sql) -> Result<Sql> {
+        match self.deleted_columns()? {
+            Some(deleted_columns) => deleted_columns.delete_sql(sql),
+            None => Ok(sql),
+        }
+    }
+
+    /// Returns the clause that revives soft deleted rows with the keys of an insert, if soft delete is set.
+    fn revive_sql(&self, insert_sql: &str) -> Result<Option<String>> {
+        Ok(self
+            .deleted_columns()?
+            .and_then(|deleted_columns| deleted_columns.revive_sql(insert_sql)))
+    }
+
+    /// Read and lock the rows with the keys of an insert of merged rows into a table with soft delete.
+    ///
+    /// Returns the number of soft deleted rows that the insert revives,
+    /// or none if the table has no soft delete column or the keys are generated.
+    /// Fails with a duplicate key error, if a row with one of the keys is not deleted.
+    fn revived_rows(
+        &mut self,
+        type_name: &str,
+        merge: &str,
+        insert_sql: &str,
+        columns: &[String],
+        keys: Vec<SqlArg>,
+    ) -> Result<Option<u64>> {
+        if self.dry_run.is_some() || keys.is_empty() || !merge_diff::inserts_columns(insert_sql, columns) {
+            return Ok(None);
+        }
+        let (table, alias, deleted) = {
+            let soft_delete = match &self.soft_delete {
+                Some(s) => s,
+                None => return Ok(None),
+            };
+            let registry = &*self.registry()?;
+            let (name, mapper) = match load_options::resolve_path(registry, type_name, merge) {
+                Some((name, mapper, true)) => (name, mapper),
+                _ => return Err(ToqlError::MapperMissing(format!("{}_{}", type_name, merge)).into()),
+            };
+            match soft_delete.deleted_column(&name) {
+                Some(deleted) => (
+                    mapper.table_name.to_owned(),
+                    mapper.canonical_table_alias.to_owned(),
+                    deleted.to_owned(),
+                ),
+                None => return Ok(None),
+            }
+        };
+        let merged_rows = MergedRows {
+            table: &table,
+            alias: &alias,
+            columns,
+        };
+        let select = merged_rows.select_deleted_sql(&deleted, keys.len() / columns.len().max(1));
+        let rows = self.select_primary(type_name, StatementKind::Merge, merge, Sql(select, keys))?;
+        let mut revived = 0;
+        for row in rows {
+            match row.0.get::<mysql::Value, _>(0) {
+                Some(mysql::Value::NULL) | None => {
+                    return Err(duplicate_key_error(format!(
+                        "Duplicate entry for a key of `{}` in table {}, the row is not deleted",
+                        merge, table
+                    )))
+                }
+                Some(_) => revived += 1,
+            }
+        }
+        Ok(Some(revived))
+    }
+
+    /// Check the affected rows of an insert that revived soft deleted rows and returns the number of written rows.
+    ///
+    /// Inserted rows count once and revived rows twice, rows that were inserted meanwhile are left unchanged.
+    fn check_revived(&self, merge: &str, affected_rows: u64, tuples: usize, revived: Option<u64>) -> Result<u64> {
+        match revived {
+            Some(revived) if affected_rows != tuples as u64 + revived => Err(duplicate_key_error(format!(
+                "Duplicate entry for a key of `{}`, a row was inserted concurrently",
+                merge
+            ))),
+            Some(_) => Ok(tuples as u64),
+            None => Ok(affected_rows),
+        }
+    }
+
+    /// Remove cached results that read a table of an executed statement.
+    ///
+    /// Within a transaction the tables are collected and invalidated when the transaction ends.
+    fn invalidate_result_cache(&mut self, sql: &str) -> Result<()> {
+        if self.result_cache.is_some() {
+            self.written_tables.extend(result_cache::statement_tables(sql));
+            if self.transaction_depth == 0 {
+                self.invalidate_written_tables()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn invalidate_written_tables(&mut self) -> Result<()> {
+        if let Some(result_cache) = self.result_cache {
+            for table in self.written_tables.drain() {
+                result_cache.invalidate(&table)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the optimizer hint block for the select of a path or an empty string.
+    ///
+    /// The block contains the maximum execution time and the hints from the load options.
+    fn select_hint(&self, path: &str) -> String {
+        let mut hints = Vec::new();
+        if let Some(d) = self.max_execution_time {
+            // Zero means no limit, round up to whole milliseconds
+            let millis = ((d.as_nanos() + 999_999) / 1_000_000).max(1);
+            hints.push(format!("MAX_EXECUTION_TIME({})", millis));
+        }
+        if let Some(load_options) = &self.load_options {
+            hints.extend(load_options.hints(path).iter().cloned());
+        }
+        if hints.is_empty() {
+            String::new()
+        } else {
+            format!("/*+ {} */", hints.join(" "))
+        }
+    }
+
+    /// Set roles
+    ///
+    /// After setting the roles all Toql functions are validated against these roles.
+    /// Roles on fields can be used to restrict the access (Only super admin can see this field, only group admin can update this field),
+    pub fn set_roles(&mut self, roles: HashSet<String>) -> &mut Self {
+        self.context.roles = roles;
+        self
+    }
+
+    /// Returns the primary connection.
+    pub fn conn(&mut self) -> &'_ mut C {
+        self.conn
+    }
+
+    /// Returns the connection for loads and counts.
+    ///
+    /// This is the replica connection, unless the read preference or a locking read requires the primary connection.
+    /// Within a transaction reads go to the primary connection to see the uncommitted writes of the transaction.
+    pub fn read_conn(&mut self) -> &'_ mut C {
+        match &mut self.replica {
+            Some(replica) if !self.reads_primary() => &mut **replica,
+            _ => &mut *self.conn,
+        }
+    }
+
+    /// Returns true, if loads and counts read from the primary connection.
+    fn reads_primary(&self) -> bool {
+        self.replica.is_none()
+            || self.read_preference.reads_primary(self.written)
+            || self.lock_mode != LockMode::None
+            || self.in_transaction()
+    }
+
+    /// Returns true, if a transaction is open on the primary connection.
+    fn in_transaction(&self) -> bool {
+        self.transaction_depth > 0 || self.conn.in_transaction()
+    }
+
+    pub fn registry(&self) -> std::result::Result<RwLockReadGuard<'_, SqlMapperRegistry>,ToqlError> {
+        self.cache.registry.read().map_err(ToqlError::from)
+    }
+    pub fn roles(&self) -> &HashSet<String> {
+        &self.context.roles
+    }
+
+    pub fn alias_format(&self) -> AliasFormat {
+        self.context.alias_format.to_owned()
+    }
+
+    /// Set alias format
+    ///
+    /// Short aliases shrink the SQL, canonical aliases are easier to read.
+    pub fn set_alias_format(&mut self, alias_format: AliasFormat) -> &mut Self {
+        self.context.alias_format = alias_format;
+        self
+    }
+
+    /// Set aux params
+    ///
+    /// Aux params are available to all SQL expressions and field handlers.
+    pub fn set_aux_params(&mut self, aux_params: HashMap<String, SqlArg>) -> &mut Self {
+        self.context.aux_params = aux_params;
+        self
+    }
+
+    pub fn aux_params(&self) -> &HashMap<String, SqlArg> {
+        &self.context.aux_params
+    }
+
+    /// Insert many structs.
+    ///
+    /// Skip fields in struct that are auto generated with `#[toql(skip_inup)]`.
+    /// Returns a report with the generated ids and affected rows per path.
+    pub fn insert_many<T, Q>(&mut self, paths: Paths<T>, mut entities: &mut [Q]) -> Result<InsertReport>
+    where
+        T: TreeInsert + Mapped + TreeIdentity + TreePredicate,
+        Q: BorrowMut<T>,
+    {
+        use toql::tree::tree_identity::IdentityAction;
+
+        // Audit rows are committed together with the mutation
+        if self.audit_trail.is_some() && self.transaction_depth == 0 {
+            return self.transaction(|mysql| mysql.insert_many::<T, Q>(paths, entities));
+        }
+
+        let type_name = <T as Mapped>::type_name();
+        let operation = Operation::new("insert", &type_name);
+        let mut report = InsertReport::new();
+
+        // Nothing to insert
+        if entities.is_empty() {
+            return Ok(report);
+        }
+
+        // Build up execution tree
+        // Path `a_b_merge1_c_d_merge2_e` becomes
+        // [0] = [a, c, e]
+        // [1] = [a_b, c_d]
+        // [m] = [merge1, merge2]
+        // Then execution order is [1], [0], [m]
+
+        // TODO should be possible to impl with &str
+        let mut joins: Vec<HashSet<String>> = Vec::new();
+        let mut merges: HashSet<String> = HashSet::new();
+
+        toql::backend::insert::plan_insert_order::<T, _>(
+            &self.registry()?.mappers,
+            &paths.list,
+            &mut joins,
+            &mut merges,
+        )?;
+
+        // Insert root
+        let sql = {
+            let aux_params = [self.aux_params()];
+            let aux_params = ParameterMap::new(&aux_params);
+            let home_path = FieldPath::default();
 
             toql::backend::insert::build_insert_sql::<T, _>(
                 &self.registry()?.mappers,
@@ -571,29 +1621,28 @@ impl<'a, C: 'a + GenericConnection> MySql<'a, C> {
             )
         }?;
         if sql.is_none() {
-            return Ok(0);
+            return Ok(report);
         }
-        let sql = sql.unwrap();
-        log_sql!(&sql);
-        dbg!(sql.to_unsafe_string());
-        let Sql(insert_stmt, insert_values) = sql;
-
-        let params = values_from(insert_values);
+        let sql = self.stamp_insert(sql.unwrap())?;
         {
-            let mut stmt = self.conn().prepare(&insert_stmt)?;
-            let res = stmt.execute(params)?;
-            let affected_rows= res.affected_rows();
+            let (affected_rows, last_insert_id) =
+                self.execute_sql(&type_name, StatementKind::Insert, "", sql.clone())?;
             if affected_rows == 0 {
-                return Ok(0);
+                report.record("", sql, affected_rows, last_insert_id);
+                operation.rows(0);
+                return Ok(report);
             }
             let home_path = FieldPath::default();
             let mut descendents = home_path.descendents();
                   toql::backend::insert::set_tree_identity(
-                    res.last_insert_id(),
-                    res.affected_rows(),
+                    last_insert_id,
+                    affected_rows,
                     &mut entities,
                     &mut descendents,
                 )?;
+            let keys = self.audit_keys::<T, Q>(&home_path, entities)?;
+            self.audit(affected_rows, || AuditEntry::inserted(&type_name, "", &sql, &keys))?;
+            report.record("", sql, affected_rows, last_insert_id);
 
           /*   if <T as toql::tree::tree_identity::TreeIdentity>::auto_id() {
                 let mut id: u64 = res.last_insert_id() + affected_rows; // first id
@@ -640,27 +1689,26 @@ impl<'a, C: 'a + GenericConnection> MySql<'a, C> {
                     )
                 }?;
                 if sql.is_none() {
-                    break;
+                    continue;
                 }
-                let sql = sql.unwrap();
-                log_sql!(&sql);
-                dbg!(sql.to_unsafe_string());
-                let Sql(insert_stmt, insert_values) = sql;
+                let sql = self.stamp_insert(sql.unwrap())?;
 
                 // Execute
-                let params = values_from(insert_values);
-                let mut stmt = self.conn().prepare(&insert_stmt)?;
-                let res = stmt.execute(params)?;
+                let (affected_rows, last_insert_id) =
+                    self.execute_sql(&type_name, StatementKind::Insert, p, sql.clone())?;
 
                 // set keys
                 let path = FieldPath::from(&p);
                 let mut descendents = path.descendents();
                 toql::backend::insert::set_tree_identity(
-                    res.last_insert_id(),
-                    res.affected_rows(),
+                    last_insert_id,
+                    affected_rows,
                     &mut entities,
                     &mut descendents,
                 )?;
+                let keys = self.audit_keys::<T, Q>(&path, entities)?;
+                self.audit(affected_rows, || AuditEntry::inserted(&type_name, p, &sql, &keys))?;
+                report.record(p, sql, affected_rows, last_insert_id);
             }
         }
 
@@ -683,36 +1731,67 @@ impl<'a, C: 'a + GenericConnection> MySql<'a, C> {
                 )
             }?;
             if sql.is_none() {
-                break;
+                continue;
             }
-            let sql = sql.unwrap();
-            log_sql!(&sql);
-            dbg!(sql.to_unsafe_string());
-            let Sql(insert_stmt, insert_values) = sql;
+            let sql = self.stamp_insert(sql.unwrap())?;
 
             // Execute
-            let params = values_from(insert_values);
-            let mut stmt = self.conn().prepare(&insert_stmt)?;
-            stmt.execute(params)?;
+            let (affected_rows, last_insert_id) =
+                self.execute_sql(&type_name, StatementKind::Insert, &p, sql.clone())?;
+            let keys = self.audit_keys::<T, Q>(&path, entities)?;
+            self.audit(affected_rows, || AuditEntry::inserted(&type_name, &p, &sql, &keys))?;
+            report.record(&p, sql, affected_rows, last_insert_id);
 
             // Merges must not contain auto value as identity, skip set_tree_identity
         }
 
-        Ok(0)
+        operation.rows(report.total_rows());
+        Ok(report)
     }
 
-    pub fn insert_one<T>(&mut self, paths: Paths<T>, entity: &mut T) -> Result<u64>
+    /// Insert one struct.
+    ///
+    /// See `insert_many` for the returned report.
+    pub fn insert_one<T>(&mut self, paths: Paths<T>, entity: &mut T) -> Result<InsertReport>
     where
-        T: TreeInsert + Mapped + TreeIdentity,
+        T: TreeInsert + Mapped + TreeIdentity + TreePredicate,
     {
         self.insert_many::<T, _>(paths, &mut [entity])
     }
 
-    /// Insert one struct.
-    ///
-    /// Skip fields in struct that are auto generated with `#[toql(skip_inup)]`.
-    /// Returns the last generated id.
-    pub fn update_many<T, Q>(&mut self, fields: Fields<T>, entities: &mut [Q]) -> Result<()>
+    /// Update many structs.
+    ///
+    /// Skip fields in struct that are auto generated with `#[toql(skip_inup)]`.
+    /// Merged entities are written according to the merge mode, see [MergeMode](merge_diff/enum.MergeMode.html).
+    /// Returns a report with the updated rows per path and the deleted and inserted rows of merges.
+    /// Versions are not checked, use `update_many_versioned` for that.
+    pub fn update_many<T, Q>(&mut self, fields: Fields<T>, entities: &mut [Q]) -> Result<UpdateReport>
+    where
+        T: TreeUpdate + Mapped + TreeIdentity + TreePredicate + TreeInsert,
+        Q: BorrowMut<T>,
+    {
+        self.update_many_with_versions::<T, Q>(fields, entities, None)
+    }
+
+    /// Update many structs like `update_many` and check their versions.
+    ///
+    /// Rows of mappers with a version field are only updated, if their version still matches,
+    /// see [concurrency](concurrency/index.html). The entities receive their new versions.
+    pub fn update_many_versioned<T, Q>(&mut self, fields: Fields<T>, entities: &mut [Q]) -> Result<UpdateReport>
+    where
+        T: TreeUpdate + Mapped + TreeIdentity + TreePredicate + TreeInsert + Versioned,
+        Q: BorrowMut<T>,
+    {
+        self.update_many_with_versions::<T, Q>(fields, entities, Some(<T as Versioned>::set_version))
+    }
+
+    /// Update many structs, versions are checked if a function to set the new versions is given.
+    fn update_many_with_versions<T, Q>(
+        &mut self,
+        fields: Fields<T>,
+        entities: &mut [Q],
+        set_version: Option<fn(&mut T, &str, SqlArg)>,
+    ) -> Result<UpdateReport>
     where
         T: TreeUpdate + Mapped + TreeIdentity + TreePredicate + TreeInsert,
         Q: BorrowMut<T>,
@@ -720,6 +1799,24 @@ impl<'a, C: 'a + GenericConnection> MySql<'a, C> {
         use toql::sql_expr::SqlExpr;
         use toql::tree::tree_identity::IdentityAction;
 
+        // Nothing to update
+        if entities.is_empty() {
+            return Ok(UpdateReport::new());
+        }
+
+        // Audit rows are committed together with the mutation
+        // and merge diffs hold the locks on the current rows until all changes are written
+        let atomic = self.audit_trail.is_some() || self.merge_mode == MergeMode::Diff;
+        if atomic && self.transaction_depth == 0 {
+            return self.transaction(|mysql| {
+                mysql.update_many_with_versions::<T, Q>(fields, entities, set_version)
+            });
+        }
+
+        let type_name = <T as Mapped>::type_name();
+        let operation = Operation::new("update", &type_name);
+        let mut report = UpdateReport::new();
+
         // TODO should be possible to impl with &str
         let mut joins: HashMap<String, HashSet<String>> = HashMap::new();
         let mut merges: HashMap<String, HashSet<String>> = HashMap::new();
@@ -733,6 +1830,16 @@ impl<'a, C: 'a + GenericConnection> MySql<'a, C> {
             &mut merges,
         )?;
 
+        // In diff mode the fields of merged entities update only kept rows
+        let mut merge_fields = HashMap::new();
+        if self.merge_mode == MergeMode::Diff {
+            for merge in merges.values().flatten() {
+                if let Some(fields) = joins.remove(merge) {
+                    merge_fields.insert(merge.to_owned(), fields);
+                }
+            }
+        }
+
         for (path, fields) in joins {
             let sqls = {
                 let field_path = FieldPath::from(&path);
@@ -748,19 +1855,51 @@ impl<'a, C: 'a + GenericConnection> MySql<'a, C> {
             }?;
 
             // Update joins
+            let versions = match set_version {
+                Some(_) => self.version_updates::<T, Q>(&type_name, &path, entities)?,
+                None => None,
+            };
             for sql in sqls {
-                dbg!(sql.to_unsafe_string());
-                execute_update_delete_sql(sql, self.conn)?;
+                let version = match &versions {
+                    Some(v) => match v.get(&sql) {
+                        Some((i, s)) => Some((i, v.kind, s)),
+                        None => return Err(ToqlMySqlError::MissingVersion(path, v.field.to_owned())),
+                    },
+                    None => None,
+                };
+                let new_version = self.execute_update(
+                    &type_name,
+                    &path,
+                    sql,
+                    None,
+                    version.map(|(_, kind, s)| (kind, s)),
+                    &mut report,
+                )?;
+                if let (Some((i, _, _)), Some(new_version), Some(set_version)) =
+                    (version, new_version, set_version)
+                {
+                    set_version(entities[i].borrow_mut(), &path, new_version);
+                }
             }
         }
 
         // Delete existing merges and insert new merges
 
+        // Process merges in deterministic order
+        let mut merges = merges.into_iter().collect::<Vec<_>>();
+        merges.sort_by(|a, b| a.0.cmp(&b.0));
+
         for (path, fields) in merges {
+            let mut fields = fields.into_iter().collect::<Vec<_>>();
+            fields.sort();
+
             // Build delete sql
 
             let parent_path = FieldPath::from(&path);
-            let entity = entities.get(0).unwrap().borrow();
+            let entity = match entities.get(0) {
+                Some(e) => e.borrow(),
+                None => break,
+            };
             let columns = <T as TreePredicate>::columns(entity, &mut parent_path.descendents())?;
             let mut args = Vec::new();
             for e in entities.iter() {
@@ -775,11 +1914,23 @@ impl<'a, C: 'a + GenericConnection> MySql<'a, C> {
             let mut key_predicate: SqlExpr = SqlExpr::new();
             key_predicate.push_predicate(columns, args);
 
-            for merge in fields {
-               let merge_path = FieldPath::from(&merge);
+            for merge in &fields {
+                if self.merge_mode == MergeMode::Diff {
+                    self.update_merge_diff::<T, Q>(
+                        &type_name,
+                        &parent_path,
+                        merge,
+                        &key_predicate,
+                        merge_fields.get(merge),
+                        entities,
+                        &mut report,
+                    )?;
+                    continue;
+                }
+
+               let merge_path = FieldPath::from(merge);
                 let sql = {
                     
-                    let type_name = <T as Mapped>::type_name();
                     let registry = &*self.registry()?;
                     let mut sql_builder = SqlBuilder::new(&type_name, registry);
                     let delete_expr =
@@ -792,8 +1943,18 @@ impl<'a, C: 'a + GenericConnection> MySql<'a, C> {
                         .map_err(ToqlError::from)?
                 };
 
-                dbg!(sql.to_unsafe_string());
-                execute_update_delete_sql(sql, self.conn)?;
+                // Reinserted rows keep their created stamps
+                let replaced = if self.audit_trail.is_some() || self.auto_stamps.is_some() {
+                    self.deleted_rows(&type_name, merge, &sql)?
+                } else {
+                    Vec::new()
+                };
+                let deleted = self.audit_deleted(&type_name, merge, &replaced);
+                let sql = self.soft_delete_sql(sql)?;
+                let (affected_rows, _) =
+                    self.execute_sql(&type_name, StatementKind::Delete, merge, sql.clone())?;
+                self.audit(affected_rows, || Ok(deleted))?;
+                report.record(StatementKind::Delete, merge, sql, affected_rows);
 
                 // Update association keys
                 for e in entities.iter_mut() {
@@ -819,19 +1980,44 @@ impl<'a, C: 'a + GenericConnection> MySql<'a, C> {
                     "",
                 )?;
                 if let Some(sql) = sql {
-                    dbg!(sql.to_unsafe_string());
-                    execute_update_delete_sql(sql, self.conn)?;
+                    let mut sql = self.stamp_insert(sql)?;
+                    if let Some(stamps) = &self.auto_stamps {
+                        sql = stamps.keep_created(sql, &replaced)?;
+                    }
+                    // Only soft deleted rows are revived, inserts of live keys fail
+                    let mut revived = None;
+                    let mut tuples = 0;
+                    if let Some(revive) = self.revive_sql(&sql.0)? {
+                        let columns = <T as TreePredicate>::columns(entities[0].borrow(), &mut merge_path.descendents())?;
+                        let mut keys = Vec::new();
+                        for e in entities.iter() {
+                            <T as TreePredicate>::args(e.borrow(), &mut merge_path.descendents(), &mut keys)?;
+                        }
+                        tuples = keys.len() / columns.len().max(1);
+                        revived = self.revived_rows(&type_name, merge, &sql.0, &columns, keys)?;
+                        sql.0.push_str(&revive);
+                    }
+                    let (affected_rows, _) =
+                        self.execute_sql(&type_name, StatementKind::Insert, merge, sql.clone())?;
+                    let affected_rows = self.check_revived(merge, affected_rows, tuples, revived)?;
+                    let keys = self.audit_keys::<T, Q>(&merge_path, entities)?;
+                    self.audit(affected_rows, || {
+                        AuditEntry::inserted(&type_name, merge, &sql, &keys)
+                    })?;
+                    report.record(StatementKind::Insert, merge, sql, affected_rows);
                 }
             }
         }
 
-        Ok(())
+        operation.rows(report.total_rows());
+        Ok(report)
     }
 
     /// Delete a struct.
     ///
     /// The field that is used as key must be attributed with `#[toql(delup_key)]`.
     /// Returns the number of deleted rows.
+    /// Rows of tables with a soft delete column are marked as deleted instead.
     /// pub fn select_one<K>(&mut self, key: K) -> Result<<K as Key>::Entity>
 
     pub fn delete_one<K>(&mut self, key: K) -> Result<u64>
@@ -853,18 +2039,24 @@ impl<'a, C: 'a + GenericConnection> MySql<'a, C> {
     ///
     /// The field that is used as key must be attributed with `#[toql(delup_key)]`.
     /// Returns the number of deleted rows.
+    /// Rows of tables with a soft delete column are marked as deleted instead.
     pub fn delete_many<T, B>(&mut self, query: B) -> Result<u64>
     where
         T: Mapped + TreeMap,
         B: Borrow<Query<T>>,
     {
-       
+        // Audit rows are committed together with the mutation
+        if self.audit_trail.is_some() && self.transaction_depth == 0 {
+            return self.transaction(|mysql| mysql.delete_many::<T, B>(query));
+        }
+
         let type_name = <T as Mapped>::type_name();
+        let operation = Operation::new("delete", &type_name);
         if !self.cache.registered_roots.read().map_err(ToqlError::from)?.contains(&type_name) {
             let mut cache = &mut *self.cache.registry.write().map_err(ToqlError::from)?;
             <T as TreeMap>::map(&mut cache)?;
 
-            self.cache.registered_roots.write().map_err(ToqlError::from)?.insert(type_name);
+            self.cache.registered_roots.write().map_err(ToqlError::from)?.insert(type_name.clone());
         }
 
         let result = SqlBuilder::new(&<T as Mapped>::type_name(), &*self.cache.registry.read().map_err(ToqlError::from)?)
@@ -882,24 +2074,680 @@ impl<'a, C: 'a + GenericConnection> MySql<'a, C> {
             let sql = result
                 .to_sql(&p, &mut alias_translator)
                 .map_err(ToqlError::from)?;
-            execute_update_delete_sql(sql, self.conn)
+            let deleted = self.deleted_entries(&type_name, "", &sql)?;
+            let sql = self.soft_delete_sql(sql)?;
+            let (rows, _) = self.execute_sql(&type_name, StatementKind::Delete, "", sql.clone())?;
+            self.audit(rows, || Ok(deleted))?;
+            operation.rows(rows);
+            Ok(rows)
         }
     }
    
+    /// Execute update of root, joined or kept merged entities.
+    ///
+    /// Adds the update stamps and the version check of a given version update and fails, if required rows are not updated.
+    /// The update of the previous entity provides the old values of the audit trail.
+    /// Returns the new version of a versioned entity.
+    fn execute_update(
+        &mut self,
+        type_name: &str,
+        path: &str,
+        sql: Sql,
+        old: Option<&Sql>,
+        version: Option<(VersionKind, &Sql)>,
+        report: &mut UpdateReport,
+    ) -> Result<Option<SqlArg>> {
+        let sql = self.stamp_update(sql)?;
+        let (sql, version_check) = match version {
+            Some((kind, version_sql)) => {
+                let (sql, check) = concurrency::apply_version(sql, version_sql, kind)?;
+                (sql, Some(check))
+            }
+            None => (sql, None),
+        };
+        let (affected_rows, _) =
+            self.execute_sql(type_name, StatementKind::Update, path, sql.clone())?;
+        if affected_rows == 0 && self.dry_run.is_none() {
+            if let Some(check) = &version_check {
+                return Err(ToqlMySqlError::ConcurrentModification {
+                    key: check.key.to_owned(),
+                });
+            }
+            if self.require_updated_rows && !self.update_matches(&sql)? {
+                let key = diff::UpdateStatement::parse(&sql)
+                    .map(|s| merge_diff::key_repr(s.tail_args))
+                    .unwrap_or_default();
+                return Err(ToqlMySqlError::NoRowsUpdated(path.to_string(), key, sql.0));
+            }
+        }
+        self.audit(affected_rows, || {
+            Ok(vec![AuditEntry::updated(type_name, path, &sql, old)])
+        })?;
+        report.record(StatementKind::Update, path, sql, affected_rows);
+
+        // New version, timestamps are read from the updated row
+        let check = match version_check {
+            Some(check) if self.dry_run.is_none() => check,
+            _ => return Ok(None),
+        };
+        if let Some(version) = check.next_counter() {
+            return Ok(Some(version));
+        }
+        let rows = self.select_primary(type_name, StatementKind::Select, path, check.select)?;
+        Ok(rows
+            .into_iter()
+            .next()
+            .and_then(|r| r.0.get::<mysql::Value, _>(0))
+            .map(arg_from))
+    }
+
+    /// Returns the version updates of the entities of a path, if its mapper has a version field.
+    ///
+    /// Entities without version update, e.g. because the version field is not set, have no entry.
+    /// Their updates fail with `MissingVersion`.
+    fn version_updates<T, Q>(
+        &self,
+        type_name: &str,
+        path: &str,
+        entities: &[Q],
+    ) -> Result<Option<VersionUpdates>>
+    where
+        T: TreeUpdate,
+        Q: Borrow<T>,
+    {
+        let (field, kind) = {
+            let version_columns = match &self.version_columns {
+                Some(v) => v,
+                None => return Ok(None),
+            };
+            let registry = &*self.registry()?;
+            let name = match load_options::resolve_path(registry, type_name, path) {
+                Some((name, _, _)) => name,
+                None => return Ok(None),
+            };
+            match version_columns.field(&name) {
+                Some((field, kind)) => (field.to_string(), kind),
+                None => return Ok(None),
+            }
+        };
+        let fields = std::iter::once(field.clone()).collect::<HashSet<_>>();
+        let field_path = FieldPath::from(path);
+        let mut versions = VersionUpdates::new(field, kind);
+        for (i, e) in entities.iter().enumerate() {
+            let sqls = toql::backend::update::build_update_sql::<T, _>(
+                self.alias_format(),
+                std::slice::from_ref(e),
+                &field_path,
+                &fields,
+                self.roles(),
+                "",
+                "",
+            )?;
+            for sql in sqls {
+                versions.insert(i, sql)?;
+            }
+        }
+        Ok(Some(versions))
+    }
+
+    /// Returns true, if the predicate of an update matches a row on the primary connection.
+    fn update_matches(&mut self, sql: &Sql) -> Result<bool> {
+        let statement = match diff::UpdateStatement::parse(sql) {
+            Some(s) => s,
+            None => return Ok(false),
+        };
+        let table = statement.head.trim_start().trim_start_matches("UPDATE");
+        let select = format!("SELECT 1 FROM{}{}", table, statement.tail);
+        instrument::statement(StatementKind::Select, "", &select, statement.tail_args, self.log_args);
+        let rows = self.conn.select(&select, values_from_ref(statement.tail_args))?;
+        Ok(!rows.is_empty())
+    }
+
+    /// Update merged entities of a path differentially.
+    ///
+    /// The keys of the current rows are read and locked on the primary connection.
+    /// Kept rows are updated with the fields of the merge path, if any.
+    /// New entities with auto increment keys get their generated keys.
+    #[allow(clippy::too_many_arguments)]
+    fn update_merge_diff<T, Q>(
+        &mut self,
+        type_name: &str,
+        parent_path: &FieldPath,
+        merge: &str,
+        parent_predicate: &toql::sql_expr::SqlExpr,
+        fields: Option<&HashSet<String>>,
+        entities: &mut [Q],
+        report: &mut UpdateReport,
+    ) -> Result<()>
+    where
+        T: TreeUpdate + Mapped + TreeIdentity + TreePredicate + TreeInsert,
+        Q: BorrowMut<T>,
+    {
+        use std::cell::RefCell;
+        use toql::tree::tree_identity::IdentityAction;
+
+        let merge_path = FieldPath::from(merge);
+        let columns = match entities.get(0) {
+            Some(e) => <T as TreePredicate>::columns(e.borrow(), &mut merge_path.descendents())?,
+            None => return Ok(()),
+        };
+
+        // Update association keys
+        for e in entities.iter_mut() {
+            let mut descendents = parent_path.descendents();
+            <T as TreeIdentity>::set_id(e.borrow_mut(), &mut descendents, &IdentityAction::Refresh)?;
+        }
+
+        // Table and alias of the merged rows and the join to their parents from the mappers
+        let mut alias_translator = AliasTranslator::new(self.alias_format());
+        let (table, alias, join) = {
+            let registry = &*self.registry()?;
+            let root_alias = &registry
+                .mappers
+                .get(type_name)
+                .ok_or_else(|| ToqlError::MapperMissing(type_name.to_string()))?
+                .canonical_table_alias;
+            let table = match load_options::resolve_path(registry, type_name, merge) {
+                Some((_, mapper, true)) => mapper.table_name.to_owned(),
+                _ => return Err(ToqlError::MapperMissing(format!("{}_{}", type_name, merge)).into()),
+            };
+            let parent_alias = match merge.rfind('_') {
+                Some(pos) => format!("{}_{}", root_alias, &merge[..pos]),
+                None => root_alias.to_owned(),
+            };
+            let merge_alias = format!("{}_{}", root_alias, merge);
+
+            let (merge_join, merge_on) = SqlBuilder::new(type_name, registry).merge_expr(merge)?;
+            let resolver = Resolver::new()
+                .with_self_alias(&parent_alias)
+                .with_other_alias(&merge_alias);
+            let mut join = resolver.resolve(&merge_join).map_err(ToqlError::from)?;
+            join.push_literal(" ON (");
+            join.extend(resolver.resolve(&merge_on).map_err(ToqlError::from)?);
+            join.push_literal(" AND ");
+            join.extend(resolver.resolve(parent_predicate).map_err(ToqlError::from)?);
+            join.push_literal(")");
+            let join = Resolver::new()
+                .to_sql(&join, &mut alias_translator)
+                .map_err(ToqlError::from)?;
+            (table, alias_translator.translate(&merge_alias), join)
+        };
+        let merged_rows = MergedRows {
+            table: &table,
+            alias: &alias,
+            columns: &columns,
+        };
+
+        // Read and lock current keys, soft deleted rows count as removed
+        let mut select = merged_rows.select_keys_sql(&join.0);
+        if let Some(deleted_columns) = self.deleted_columns()? {
+            deleted_columns.exclude_deleted(&mut select);
+        }
+        // Dry runs only read
+        if self.dry_run.is_some() {
+            if let Some(unlocked) = select.strip_suffix(" FOR UPDATE") {
+                select = unlocked.to_string();
+            }
+        }
+        let rows = self.select_primary(type_name, StatementKind::Merge, merge, Sql(select, join.1))?;
+        let current = rows
+            .iter()
+            .map(|r| {
+                (0..columns.len())
+                    .map(|i| arg_from(r.0.get::<mysql::Value, _>(i).unwrap_or(mysql::Value::NULL)))
+                    .collect::<Vec<_>>()
+            })
+            .map(|k| (merge_diff::key_repr(&k), k))
+            .collect::<BTreeMap<_, _>>();
+
+        // Keys of the merged entities in insert order
+        let mut args = Vec::new();
+        for e in entities.iter() {
+            <T as TreePredicate>::args(e.borrow(), &mut merge_path.descendents(), &mut args)?;
+        }
+        let keys = args
+            .chunks(columns.len())
+            .map(merge_diff::key_repr)
+            .collect::<Vec<_>>();
+
+        // Delete removed
+        let removed = current
+            .iter()
+            .filter(|(r, _)| !keys.contains(r))
+            .flat_map(|(_, k)| k.to_owned())
+            .collect::<Vec<_>>();
+        if !removed.is_empty() {
+            let sql = Sql(
+                merged_rows.delete_keys_sql(removed.len() / columns.len()),
+                removed,
+            );
+            let deleted = self.deleted_entries(type_name, merge, &sql)?;
+            let sql = self.soft_delete_sql(sql)?;
+            let (affected_rows, _) =
+                self.execute_sql(type_name, StatementKind::Delete, merge, sql.clone())?;
+            self.audit(affected_rows, || Ok(deleted))?;
+            report.record(StatementKind::Delete, merge, sql, affected_rows);
+        }
+
+        // Update kept with the fields of the merge path
+        if let Some(fields) = fields {
+            let sqls = toql::backend::update::build_update_sql::<T, _>(
+                self.alias_format(),
+                entities,
+                &merge_path,
+                fields,
+                self.roles(),
+                "",
+                "",
+            )?;
+            for sql in sqls {
+                let kept = diff::UpdateStatement::parse(&sql)
+                    .map_or(true, |s| current.contains_key(&merge_diff::key_repr(s.tail_args)));
+                if kept {
+                    self.execute_update(type_name, merge, sql, None, None, report)?;
+                }
+            }
+        }
+
+        // Insert new, soft deleted keys are revived
+        let new = keys
+            .iter()
+            .map(|k| !current.contains_key(k))
+            .collect::<Vec<_>>();
+        if !new.contains(&true) {
+            return Ok(());
+        }
+        let insert_sql = {
+            let aux_params = [self.aux_params()];
+            let aux_params = ParameterMap::new(&aux_params);
+            toql::backend::insert::build_insert_sql(
+                &self.registry()?.mappers,
+                self.alias_format(),
+                &aux_params,
+                entities,
+                &self.roles(),
+                &merge_path,
+                "",
+                "",
+            )?
+        };
+        if let Some(sql) = insert_sql {
+            let auto_increment = !merge_diff::inserts_columns(&sql.0, &columns);
+            let mut sql = match diff::InsertStatement::parse(&sql) {
+                Some(mut statement) if statement.tuples.len() == new.len() => {
+                    statement.retain_tuples(&new);
+                    statement.to_sql()
+                }
+                _ => return Err(ToqlMySqlError::UnexpectedStatement(sql.0)),
+            };
+            sql = self.stamp_insert(sql)?;
+
+            // Only soft deleted rows are revived, inserts of live keys fail
+            let tuples = new.iter().filter(|n| **n).count();
+            let mut revived = None;
+            if let Some(revive) = self.revive_sql(&sql.0)? {
+                let new_keys = args
+                    .chunks(columns.len())
+                    .zip(&new)
+                    .filter(|(_, n)| **n)
+                    .flat_map(|(k, _)| k.to_vec())
+                    .collect::<Vec<_>>();
+                revived = self.revived_rows(type_name, merge, &sql.0, &columns, new_keys)?;
+                sql.0.push_str(&revive);
+            }
+            let (affected_rows, last_insert_id) =
+                self.execute_sql(type_name, StatementKind::Insert, merge, sql.clone())?;
+            let affected_rows = self.check_revived(merge, affected_rows, tuples, revived)?;
+
+            // Set generated keys of new entities, consecutive for a multiple row insert
+            if auto_increment && affected_rows > 0 {
+                let ids = (0..affected_rows)
+                    .map(|i| SqlArg::U64(last_insert_id + i))
+                    .collect::<Vec<_>>();
+                let action = IdentityAction::SetInvalid(RefCell::new(ids));
+                for e in entities.iter_mut() {
+                    let mut descendents = merge_path.descendents();
+                    <T as TreeIdentity>::set_id(e.borrow_mut(), &mut descendents, &action)?;
+                }
+            }
+
+            // Keys of the inserted tuples
+            let keys = self
+                .audit_keys::<T, Q>(&merge_path, entities)?
+                .into_iter()
+                .zip(&new)
+                .filter(|(_, n)| **n)
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>();
+            self.audit(affected_rows, || {
+                AuditEntry::inserted(type_name, merge, &sql, &keys)
+            })?;
+            report.record(StatementKind::Insert, merge, sql, affected_rows);
+        }
+        Ok(())
+    }
+
     /// Update a single struct.
     ///
     /// Optional fields with value `None` are not updated. See guide for details.
     /// The field that is used as key must be attributed with `#[toql(key)]`.
-    /// Returns the number of updated rows.
-    ///
-
-    pub fn update_one<T>(&mut self, fields: Fields<T>, entity: &mut T) -> Result<()>
+    /// See `update_many` for the returned report.
+    pub fn update_one<T>(&mut self, fields: Fields<T>, entity: &mut T) -> Result<UpdateReport>
     where
         T: TreeUpdate + Mapped + TreeIdentity + TreePredicate + TreeInsert,
     {
         self.update_many::<T, _>(fields, &mut [entity])
     }
 
+    /// Update a single struct like `update_one` and check its version, see `update_many_versioned`.
+    pub fn update_one_versioned<T>(&mut self, fields: Fields<T>, entity: &mut T) -> Result<UpdateReport>
+    where
+        T: TreeUpdate + Mapped + TreeIdentity + TreePredicate + TreeInsert + Versioned,
+    {
+        self.update_many_versioned::<T, _>(fields, &mut [entity])
+    }
+
+    /// Update the fields and merges of an entity that changed.
+    ///
+    /// Unlike a full diff, only the fields and merges in `fields` are compared with the previous version.
+    /// Changes outside of `fields` are not written. See [diff](diff/index.html) for details.
+    pub fn update_changed<T>(&mut self, fields: Fields<T>, old: &T, new: &mut T) -> Result<UpdateReport>
+    where
+        T: TreeUpdate + Mapped + TreeIdentity + TreePredicate + TreeInsert + Keyed,
+        <T as Keyed>::Key: Key,
+    {
+        self.update_changed_many(fields, std::slice::from_ref(old), std::slice::from_mut(new))
+    }
+
+    /// Update the fields and merges of many entities that changed.
+    ///
+    /// Like `update_changed` only the fields and merges in `fields` are compared.
+    /// Entities are matched with their previous versions by key.
+    /// Entities without previous version are skipped, use `sync_many` to insert them.
+    /// Changed merges are written in `MergeMode::Diff`.
+    pub fn update_changed_many<T>(
+        &mut self,
+        fields: Fields<T>,
+        old: &[T],
+        new: &mut [T],
+    ) -> Result<UpdateReport>
+    where
+        T: TreeUpdate + Mapped + TreeIdentity + TreePredicate + TreeInsert + Keyed,
+        <T as Keyed>::Key: Key,
+    {
+        // Audit rows are committed together with the mutation
+        if self.audit_trail.is_some() && self.transaction_depth == 0 {
+            return self.transaction(|mysql| mysql.update_changed_many(fields, old, new));
+        }
+
+        let type_name = <T as Mapped>::type_name();
+        let operation = Operation::new("update", &type_name);
+        let mut report = UpdateReport::new();
+
+        // Nothing to update
+        if new.is_empty() {
+            return Ok(report);
+        }
+
+        let old_by_key = old
+            .iter()
+            .map(|e| (merge_diff::key_repr(&Key::params(&e.key())), e))
+            .collect::<HashMap<_, _>>();
+
+        let mut joins: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut merges: HashMap<String, HashSet<String>> = HashMap::new();
+        toql::backend::update::plan_update_order::<T, _>(
+            &self.registry()?.mappers,
+            &fields.list,
+            &mut joins,
+            &mut merges,
+        )?;
+        let mut merge_paths = merges.into_iter().flat_map(|(_, m)| m).collect::<Vec<_>>();
+        merge_paths.sort();
+
+        // Fields of merged entities are written with their merge
+        let mut merge_fields = HashMap::new();
+        for merge in &merge_paths {
+            if let Some(fields) = joins.remove(merge) {
+                let fields = fields
+                    .into_iter()
+                    .map(|f| format!("{}_{}", merge, f))
+                    .collect::<Vec<_>>();
+                merge_fields.insert(merge.to_owned(), fields);
+            }
+        }
+        let mut joins = joins.into_iter().collect::<Vec<_>>();
+        joins.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // Compare entities
+        let mut updates = Vec::new();
+        let mut changed_merges: BTreeMap<String, HashSet<usize>> = BTreeMap::new();
+        for (i, n) in new.iter().enumerate() {
+            let o = match old_by_key.get(&merge_diff::key_repr(&Key::params(&n.key()))) {
+                Some(o) => o,
+                None => continue,
+            };
+
+            for (path, fields) in &joins {
+                let field_path = FieldPath::from(path);
+                let old_sqls = toql::backend::update::build_update_sql::<T, _>(
+                    self.alias_format(),
+                    std::slice::from_ref(*o),
+                    &field_path,
+                    fields,
+                    self.roles(),
+                    "",
+                    "",
+                )?;
+                let new_sqls = toql::backend::update::build_update_sql::<T, _>(
+                    self.alias_format(),
+                    std::slice::from_ref(n),
+                    &field_path,
+                    fields,
+                    self.roles(),
+                    "",
+                    "",
+                )?;
+                for new_sql in &new_sqls {
+                    let old_sql = old_sqls.iter().find(|o| diff::same_target(o, new_sql));
+                    let changed = match old_sql {
+                        Some(old_sql) => diff::changed_update(old_sql, new_sql),
+                        None => Some(new_sql.to_owned()),
+                    };
+                    if let Some(sql) = changed {
+                        updates.push((path.to_owned(), sql, old_sql.cloned()));
+                    }
+                }
+            }
+
+            for merge in &merge_paths {
+                let merge_path = FieldPath::from(merge);
+                let aux_params = [self.aux_params()];
+                let aux_params = ParameterMap::new(&aux_params);
+                let registry = self.registry()?;
+                let old_sql = toql::backend::insert::build_insert_sql::<T, _>(
+                    &registry.mappers,
+                    self.alias_format(),
+                    &aux_params,
+                    std::slice::from_ref(*o),
+                    &self.roles(),
+                    &merge_path,
+                    "",
+                    "",
+                )?;
+                let new_sql = toql::backend::insert::build_insert_sql::<T, _>(
+                    &registry.mappers,
+                    self.alias_format(),
+                    &aux_params,
+                    std::slice::from_ref(n),
+                    &self.roles(),
+                    &merge_path,
+                    "",
+                    "",
+                )?;
+                if !diff::same_sql(&old_sql, &new_sql) {
+                    changed_merges.entry(merge.to_owned()).or_default().insert(i);
+                }
+            }
+        }
+
+        if updates.is_empty() && changed_merges.is_empty() {
+            return Ok(report);
+        }
+
+        // Update changed columns
+        for (path, sql, old_sql) in updates {
+            self.execute_update(&type_name, &path, sql, old_sql.as_ref(), None, &mut report)?;
+        }
+
+        // Update changed merges of the affected entities, only changed merged rows are written
+        let merge_mode = std::mem::replace(&mut self.merge_mode, MergeMode::Diff);
+        let mut merge_result = Ok(());
+        for (merge, indexes) in changed_merges {
+            let mut entities = new
+                .iter_mut()
+                .enumerate()
+                .filter(|(i, _)| indexes.contains(i))
+                .map(|(_, e)| e)
+                .collect::<Vec<_>>();
+            let mut fields = merge_fields.remove(&merge).unwrap_or_default();
+            fields.push(merge);
+            match self.update_many::<T, _>(Fields::from(fields), &mut entities) {
+                Ok(merge_report) => report.extend(merge_report),
+                Err(e) => {
+                    merge_result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.merge_mode = merge_mode;
+        merge_result?;
+
+        operation.rows(report.total_rows());
+        Ok(report)
+    }
+
+    /// Synchronize a collection with its outdated version.
+    ///
+    /// Entities are matched by their key. Entities that are only in `updated` are inserted with `paths`,
+    /// entities that differ are updated with `fields` and entities that are only in `outdated` are deleted.
+    /// All statements run in one transaction, see [transaction](#method.transaction).
+    pub fn sync_many<T>(
+        &mut self,
+        outdated: &[T],
+        updated: &mut [T],
+        fields: Fields<T>,
+        paths: Paths<T>,
+    ) -> Result<SyncReport>
+    where
+        T: TreeInsert
+            + TreeUpdate
+            + TreeIdentity
+            + TreePredicate
+            + TreeMap
+            + Mapped
+            + Keyed
+            + PartialEq,
+        <T as Keyed>::Key: Key<Entity = T> + Into<Query<T>>,
+    {
+        let outdated_keys = outdated
+            .iter()
+            .map(|e| (merge_diff::key_repr(&Key::params(&e.key())), e))
+            .collect::<HashMap<_, _>>();
+        let updated_keys = updated
+            .iter()
+            .map(|e| merge_diff::key_repr(&Key::params(&e.key())))
+            .collect::<HashSet<_>>();
+
+        let mut inserts: Vec<&mut T> = Vec::new();
+        let mut updates: Vec<&mut T> = Vec::new();
+        for e in updated.iter_mut() {
+            match outdated_keys.get(&merge_diff::key_repr(&Key::params(&e.key()))) {
+                None => inserts.push(e),
+                Some(o) if *o != &*e => updates.push(e),
+                Some(_) => {}
+            }
+        }
+        let deletes = outdated
+            .iter()
+            .filter(|e| !updated_keys.contains(&merge_diff::key_repr(&Key::params(&e.key()))))
+            .map(|e| e.key())
+            .collect::<Vec<_>>();
+
+        let mut report = SyncReport::new();
+        if inserts.is_empty() && updates.is_empty() && deletes.is_empty() {
+            return Ok(report);
+        }
+
+        // Delete all missing entities with one statement
+        let mut deletes = deletes.into_iter().map(|k| -> Query<T> { k.into() });
+        let delete_query = deletes
+            .next()
+            .map(|first| deletes.fold(first, |query, key| query.or_parentized(key)));
+
+        self.transaction(|mysql| {
+            if !inserts.is_empty() {
+                report.inserted = mysql.insert_many::<T, _>(paths, &mut inserts)?;
+            }
+            if !updates.is_empty() {
+                report.updated = mysql.update_many::<T, _>(fields, &mut updates)?;
+            }
+            if let Some(query) = delete_query {
+                report.deleted = mysql.delete_many(query)?;
+            }
+            Ok(())
+        })?;
+        Ok(report)
+    }
+
+    /// Run function in a transaction.
+    ///
+    /// The transaction is committed, if the function succeeds, and rolled back otherwise.
+    /// Within an open transaction, a `mysql::Transaction` or a nested call, a savepoint is used instead.
+    /// Cached results of written tables are invalidated, when the outermost call ends.
+    pub fn transaction<F, R>(&mut self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut Self) -> Result<R>,
+    {
+        let savepoint = if self.transaction_depth > 0 || self.conn.in_transaction() {
+            Some(format!("toql_{}", self.transaction_depth))
+        } else {
+            None
+        };
+        match &savepoint {
+            Some(name) => self.query_drop(&format!("SAVEPOINT {}", name))?,
+            None => self.query_drop("START TRANSACTION")?,
+        }
+
+        self.transaction_depth += 1;
+        let result = f(self);
+        self.transaction_depth -= 1;
+
+        let result = match result {
+            Ok(r) => {
+                let end = match &savepoint {
+                    Some(name) => self.query_drop(&format!("RELEASE SAVEPOINT {}", name)),
+                    None => self.query_drop("COMMIT"),
+                };
+                end.map(|_| r)
+            }
+            Err(e) => {
+                let rollback = match &savepoint {
+                    Some(name) => self.query_drop(&format!("ROLLBACK TO SAVEPOINT {}", name)),
+                    None => self.query_drop("ROLLBACK"),
+                };
+                if let Err(rollback_error) = rollback {
+                    log::warn!("Unable to rollback transaction: {:?}", rollback_error);
+                }
+                Err(e)
+            }
+        };
+        if self.transaction_depth == 0 {
+            self.invalidate_written_tables()?;
+        }
+        result
+    }
+
     /// Counts the number of rows that match the query predicate.
     ///
     /// Returns a struct or a [ToqlMySqlError](../toql/error/enum.ToqlMySqlError.html) if no struct was found _NotFound_ or more than one _NotUnique_.
@@ -914,6 +2762,7 @@ impl<'a, C: 'a + GenericConnection> MySql<'a, C> {
         .get(&<T as Mapped>::type_name())
         .ok_or(ToqlError::MapperMissing(<T as Mapped>::type_name()))?; */
 
+        let operation = Operation::new("count", &<T as Mapped>::type_name());
         let mut alias_translator = AliasTranslator::new(self.alias_format());
 
         let result = SqlBuilder::new(&<T as Mapped>::type_name(), &*self.cache.registry.read().map_err(ToqlError::from)?)
@@ -923,14 +2772,15 @@ impl<'a, C: 'a + GenericConnection> MySql<'a, C> {
         let p = [self.aux_params()];
         let aux_params = ParameterMap::new(&p);
 
-        let sql = result
+        let mut sql = result
             .to_sql(&aux_params, &mut alias_translator)
             .map_err(ToqlError::from)?;
+        self.exclude_deleted(&mut sql.0)?;
 
-        log_sql!(sql);
-        let result = self.conn.prep_exec(&sql.0, values_from_ref(&sql.1))?;
+        let rows = self.select_rows(&<T as Mapped>::type_name(), StatementKind::Count, "", sql)?;
 
-        let count = result.into_iter().next().unwrap().unwrap().get(0).unwrap();
+        let count = count_value(rows)?;
+        operation.rows(1);
 
         Ok(count)
     }
@@ -1002,4 +2852,38 @@ impl<'a, C: 'a + GenericConnection> MySql<'a, C> {
 
         Ok(entities_page)
     }
+
+    /// Explain the statements of a load without running them.
+    ///
+    /// Returns the root select, the count selects for a counted page and the merge selects for each path
+    /// in execution order. Merge selects are built without the key predicate of the loaded entities.
+    pub fn explain<T, B>(&mut self, query: B, page: Option<Page>) -> Result<Vec<ExplainedStatement>>
+    where
+        T: Mapped + TreeMap + TreePredicate,
+        B: Borrow<Query<T>>,
+    {
+        explain_load(self, query.borrow(), page.as_ref(), false)
+    }
+
+    /// Explain the statements of a load like `explain` and include the query plan of the server for each statement.
+    pub fn explain_with_plans<T, B>(&mut self, query: B, page: Option<Page>) -> Result<Vec<ExplainedStatement>>
+    where
+        T: Mapped + TreeMap + TreePredicate,
+        B: Borrow<Query<T>>,
+    {
+        explain_load(self, query.borrow(), page.as_ref(), true)
+    }
+}
+
+impl<'a, C: 'a + GenericConnection + Connection> MySql<'a, C> {
+    /// Apply session settings to primary and replica connection and verify them.
+    ///
+    /// Use this for single connections, pools should pass the settings to their connection options.
+    pub fn apply_session_settings(&mut self, settings: &SessionSettings) -> Result<&mut Self> {
+        settings.apply(self.conn)?;
+        if let Some(replica) = &mut self.replica {
+            settings.apply(&mut **replica)?;
+        }
+        Ok(self)
+    }
 }
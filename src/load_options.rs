@@ -0,0 +1,339 @@
+//! Optimizer and index hints for loads.
+//!
+//! [LoadOptions](struct.LoadOptions.html) attach `/*+ ... */` optimizer hints and
+//! `USE INDEX`, `FORCE INDEX` or `IGNORE INDEX` hints to the root select and the merge selects of a load.
+//!
+//! Index hints refer to canonical table aliases, such as `user` for the root table
+//! and `user_address` for the joined path `address`.
+//! Loads validate the aliases and the merge paths of hints against the mappers.
+//!
+//! ```ignore
+//! let options = LoadOptions::new()
+//!     .hint("BKA(user_address)")
+//!     .merge_hint("phones", "NO_ICP(user_phones)")
+//!     .force_index("user_address", &["idx_city"]);
+//! toql.set_load_options(Some(options));
+//! let users = toql.load_many(query!(User, "*, address_*, phones_*"))?;
+//! ```
+
+use std::collections::HashMap;
+
+use toql::{
+    alias_translator::AliasTranslator, error::ToqlError, sql_mapper::SqlMapper,
+    sql_mapper_registry::SqlMapperRegistry,
+};
+
+use crate::{
+    diff::scan,
+    error::{Result, ToqlMySqlError},
+};
+
+/// Kind of index hint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexHintKind {
+    Use,
+    Force,
+    Ignore,
+}
+
+/// Index hint for a table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexHint {
+    pub kind: IndexHintKind,
+    pub indexes: Vec<String>,
+}
+
+impl IndexHint {
+    fn to_sql(&self) -> String {
+        let kind = match self.kind {
+            IndexHintKind::Use => "USE",
+            IndexHintKind::Force => "FORCE",
+            IndexHintKind::Ignore => "IGNORE",
+        };
+        format!("{} INDEX ({})", kind, self.indexes.join(", "))
+    }
+}
+
+/// Hints for root and merge selects.
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    hints: HashMap<String, Vec<String>>,
+    index_hints: HashMap<String, Vec<IndexHint>>,
+}
+
+impl LoadOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add optimizer hint to the root select, e.g. `BKA(user_address)`.
+    pub fn hint<S: Into<String>>(self, hint: S) -> Self {
+        self.merge_hint("", hint)
+    }
+
+    /// Add optimizer hint to the merge select of a path.
+    pub fn merge_hint<P: Into<String>, S: Into<String>>(mut self, path: P, hint: S) -> Self {
+        self.hints
+            .entry(path.into())
+            .or_insert_with(Vec::new)
+            .push(hint.into());
+        self
+    }
+
+    /// Add `USE INDEX` hint for the table with the canonical alias.
+    pub fn use_index<S: Into<String>>(self, table_alias: S, indexes: &[&str]) -> Self {
+        self.index_hint(table_alias, IndexHintKind::Use, indexes)
+    }
+
+    /// Add `FORCE INDEX` hint for the table with the canonical alias.
+    pub fn force_index<S: Into<String>>(self, table_alias: S, indexes: &[&str]) -> Self {
+        self.index_hint(table_alias, IndexHintKind::Force, indexes)
+    }
+
+    /// Add `IGNORE INDEX` hint for the table with the canonical alias.
+    pub fn ignore_index<S: Into<String>>(self, table_alias: S, indexes: &[&str]) -> Self {
+        self.index_hint(table_alias, IndexHintKind::Ignore, indexes)
+    }
+
+    fn index_hint<S: Into<String>>(
+        mut self,
+        table_alias: S,
+        kind: IndexHintKind,
+        indexes: &[&str],
+    ) -> Self {
+        self.index_hints
+            .entry(table_alias.into())
+            .or_insert_with(Vec::new)
+            .push(IndexHint {
+                kind,
+                indexes: indexes.iter().map(|i| i.to_string()).collect(),
+            });
+        self
+    }
+
+    /// Returns the optimizer hints for the select of a path. The root path is empty.
+    pub fn hints(&self, path: &str) -> &[String] {
+        self.hints.get(path).map(|h| h.as_slice()).unwrap_or(&[])
+    }
+
+    /// Returns true, if no hints are set.
+    pub fn is_empty(&self) -> bool {
+        self.hints.is_empty() && self.index_hints.is_empty()
+    }
+
+    /// Returns a representation of all hints that does not depend on their insertion order.
+    pub(crate) fn cache_key(&self) -> String {
+        let mut hints = self.hints.iter().collect::<Vec<_>>();
+        hints.sort();
+        let mut index_hints = self
+            .index_hints
+            .iter()
+            .map(|(alias, hints)| {
+                (
+                    alias,
+                    hints.iter().map(IndexHint::to_sql).collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        index_hints.sort();
+        format!("{:?}{:?}", hints, index_hints)
+    }
+
+    /// Validate hints against the mappers of a root type.
+    ///
+    /// Index hints must refer to the root alias or to the alias of a joined or merged path.
+    /// Optimizer hints must belong to the root or to a merged path.
+    /// Index names and optimizer hints must not contain characters that could escape the hint.
+    pub fn validate(&self, registry: &SqlMapperRegistry, type_name: &str) -> Result<()> {
+        let root_alias = &registry
+            .mappers
+            .get(type_name)
+            .ok_or_else(|| ToqlError::MapperMissing(type_name.to_string()))?
+            .canonical_table_alias;
+        let path_prefix = format!("{}_", root_alias);
+        for (alias, hints) in &self.index_hints {
+            let path = if alias == root_alias {
+                Some("")
+            } else {
+                alias.strip_prefix(&path_prefix)
+            };
+            if path.and_then(|p| resolve_path(registry, type_name, p)).is_none() {
+                return Err(ToqlMySqlError::InvalidHint(format!(
+                    "table alias `{}` does not belong to `{}`",
+                    alias, root_alias
+                )));
+            }
+            for hint in hints {
+                if hint.indexes.is_empty() {
+                    return Err(ToqlMySqlError::InvalidHint(format!(
+                        "missing index names for table alias `{}`",
+                        alias
+                    )));
+                }
+                if let Some(index) = hint.indexes.iter().find(|i| !is_identifier(i)) {
+                    return Err(ToqlMySqlError::InvalidHint(format!(
+                        "invalid index name `{}`",
+                        index
+                    )));
+                }
+            }
+        }
+        for path in self.hints.keys() {
+            let merged = resolve_path(registry, type_name, path).map(|(_, _, merged)| merged);
+            if !path.is_empty() && merged != Some(true) {
+                return Err(ToqlMySqlError::InvalidHint(format!(
+                    "`{}` is no merged path of `{}`",
+                    path, type_name
+                )));
+            }
+        }
+        for hint in self.hints.values().flatten() {
+            if hint.contains("*/") || hint.contains("/*") || hint.contains(';') {
+                return Err(ToqlMySqlError::InvalidHint(format!(
+                    "invalid optimizer hint `{}`",
+                    hint
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Insert index hints into the table references of a select statement.
+    ///
+    /// Table references are found after `FROM` and `JOIN` keywords.
+    /// Aliases that do not occur in the statement are ignored.
+    pub fn apply_index_hints(&self, sql: &mut String, alias_translator: &mut AliasTranslator) {
+        for (canonical_alias, hints) in &self.index_hints {
+            let alias = alias_translator.translate(canonical_alias);
+            let hint_sql = hints
+                .iter()
+                .map(IndexHint::to_sql)
+                .collect::<Vec<_>>()
+                .join(" ");
+            if let Some(pos) = find_table_reference(sql, &alias) {
+                sql.insert_str(pos, &format!(" {}", hint_sql));
+            }
+        }
+    }
+}
+
+/// Follow a field path through the joins and merges of the mappers.
+///
+/// Returns none for an unknown path and otherwise the name and the mapper at the end of the path
+/// and true, if the last step is a merge.
+pub(crate) fn resolve_path<'r>(
+    registry: &'r SqlMapperRegistry,
+    type_name: &str,
+    path: &str,
+) -> Option<(String, &'r SqlMapper, bool)> {
+    let mut name = type_name.to_string();
+    let mut mapper = registry.mappers.get(type_name)?;
+    let mut merged = false;
+    for step in path.split('_').filter(|s| !s.is_empty()) {
+        let next = match mapper.joined_mapper(step) {
+            Some(joined) => {
+                merged = false;
+                joined
+            }
+            None => {
+                merged = true;
+                mapper.merged_mapper(step)?
+            }
+        };
+        mapper = registry.mappers.get(&next)?;
+        name = next;
+    }
+    Some((name, mapper, merged))
+}
+
+/// Returns the position after the table alias in a `FROM` or `JOIN` table reference.
+fn find_table_reference(sql: &str, alias: &str) -> Option<usize> {
+    let bytes = sql.as_bytes();
+    let mut start = 0;
+    while let Some(found) = next_keyword(&sql[start..]) {
+        let mut pos = start + found;
+        pos = skip(bytes, pos, |b| b.is_ascii_whitespace() || b == b'(');
+        pos = skip(bytes, pos, |b| !b.is_ascii_whitespace() && b != b'(');
+        pos = skip(bytes, pos, |b| b.is_ascii_whitespace());
+        let alias_start = pos;
+        pos = skip(bytes, pos, |b| {
+            !b.is_ascii_whitespace() && b != b')' && b != b','
+        });
+        if &sql[alias_start..pos] == alias {
+            return Some(pos);
+        }
+        start = alias_start;
+    }
+    None
+}
+
+/// Returns the position after the next `FROM` or `JOIN` keyword.
+///
+/// Keywords must stand alone and are ignored in string literals and quoted identifiers.
+fn next_keyword(sql: &str) -> Option<usize> {
+    let bytes = sql.as_bytes();
+    let mut found = None;
+    scan(sql, |i, _, _| {
+        if found.is_some() || (i > 0 && is_identifier_byte(bytes[i - 1])) {
+            return;
+        }
+        for keyword in &["FROM", "JOIN"] {
+            let end = i + keyword.len();
+            if sql[i..].starts_with(keyword) && bytes.get(end).map_or(false, |b| b.is_ascii_whitespace()) {
+                found = Some(end);
+            }
+        }
+    });
+    found
+}
+
+fn skip<F: Fn(u8) -> bool>(bytes: &[u8], mut pos: usize, f: F) -> usize {
+    while pos < bytes.len() && f(bytes[pos]) {
+        pos += 1;
+    }
+    pos
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(is_identifier_byte)
+}
+
+fn is_identifier_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'$'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_table_reference, next_keyword};
+
+    #[test]
+    fn finds_standalone_keywords() {
+        let sql = "SELECT user.id FROM User user";
+        assert_eq!(next_keyword(sql), Some(sql.find(" User").unwrap()));
+        let sql = "SELECT user.JOINED, user.x_FROM FROM User user";
+        assert_eq!(next_keyword(sql), Some(sql.find(" User").unwrap()));
+        assert_eq!(next_keyword("SELECT 1"), None);
+    }
+
+    #[test]
+    fn ignores_keywords_in_literals() {
+        let sql = "SELECT 'FROM x', `JOIN y` FROM User user";
+        assert_eq!(next_keyword(sql), Some(sql.find(" User").unwrap()));
+    }
+
+    #[test]
+    fn finds_table_references_of_aliases() {
+        let sql = "SELECT user.id FROM User user \
+                   JOIN (Address user_address) ON (user.address_id = user_address.id) \
+                   WHERE user.id = ?";
+        assert_eq!(
+            find_table_reference(sql, "user"),
+            Some(sql.find("User user").unwrap() + "User user".len())
+        );
+        assert_eq!(
+            find_table_reference(sql, "user_address"),
+            Some(sql.find("Address user_address").unwrap() + "Address user_address".len())
+        );
+        assert_eq!(find_table_reference(sql, "user_phones"), None);
+    }
+}
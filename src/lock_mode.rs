@@ -0,0 +1,38 @@
+//! Locking reads for loads.
+//!
+//! With a [LockMode](enum.LockMode.html) other than `None` the root and merge selects of loads
+//! lock the selected rows. Locks only make sense on the primary, so all reads go to the primary connection
+//! while a lock mode is set.
+//!
+//! ```ignore
+//! toql.set_lock_mode(LockMode::Update);
+//! let user = toql.load_one(query!(User, "*, id eq 5"))?;
+//! ```
+
+/// Locking read for root and merge selects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+    /// Plain consistent read
+    None,
+    /// `LOCK IN SHARE MODE`
+    Share,
+    /// `FOR UPDATE`
+    Update,
+}
+
+impl Default for LockMode {
+    fn default() -> Self {
+        LockMode::None
+    }
+}
+
+impl LockMode {
+    /// Returns the locking clause or an empty string.
+    pub fn to_sql(&self) -> &'static str {
+        match self {
+            LockMode::None => "",
+            LockMode::Share => "LOCK IN SHARE MODE",
+            LockMode::Update => "FOR UPDATE",
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Differential merge updates.
+//!
+//! By default `update_many` replaces merged entities: all rows of a merge path are deleted and inserted again.
+//! With [MergeMode::Diff](enum.MergeMode.html) the keys of the current rows are read and locked on the primary connection instead.
+//! Only removed rows are deleted, kept rows are updated with the fields of the merge path and new rows are inserted, in this order.
+//! Paths are processed in alphabetical order.
+//! The whole update runs in one transaction, so that the locks last until all paths are written
+//! and a failure leaves no path partially updated.
+//!
+//! Merged entities with auto increment keys are new, if their key is not in the database.
+//! They get the generated keys after the insert.
+//!
+//! ```ignore
+//! toql.set_merge_mode(MergeMode::Diff);
+//! let report = toql.update_many(fields!(User, "phones, phones_number"), &mut users)?;
+//! println!("Phones deleted {}, inserted {}", report.deleted("phones"), report.inserted("phones"));
+//! ```
+
+use toql::sql_arg::SqlArg;
+
+/// How `update_many` writes merged entities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeMode {
+    /// Delete all merged rows and insert them again
+    Replace,
+    /// Delete removed rows, update kept rows and insert new rows
+    Diff,
+}
+
+impl Default for MergeMode {
+    fn default() -> Self {
+        MergeMode::Replace
+    }
+}
+
+/// Table, alias and key columns of the rows of a merge path.
+///
+/// Table and alias come from the mapper of the merged entities.
+pub(crate) struct MergedRows<'a> {
+    pub(crate) table: &'a str,
+    pub(crate) alias: &'a str,
+    pub(crate) columns: &'a [String],
+}
+
+impl<'a> MergedRows<'a> {
+    /// Select and lock the keys of the merged rows, the join restricts them to the parent entities.
+    pub(crate) fn select_keys_sql(&self, join: &str) -> String {
+        format!(
+            "SELECT {} FROM {} {} {} FOR UPDATE",
+            self.qualified().join(", "),
+            self.table,
+            self.alias,
+            join
+        )
+    }
+
+    /// Delete merged rows by their keys.
+    pub(crate) fn delete_keys_sql(&self, number_of_keys: usize) -> String {
+        format!(
+            "DELETE {} FROM {} {} WHERE {}",
+            self.alias,
+            self.table,
+            self.alias,
+            self.keys_predicate(number_of_keys)
+        )
+    }
+
+    /// Select and lock the deletion timestamp of merged rows by their keys.
+    pub(crate) fn select_deleted_sql(&self, deleted_column: &str, number_of_keys: usize) -> String {
+        format!(
+            "SELECT {}.{} FROM {} {} WHERE {} FOR UPDATE",
+            self.alias,
+            deleted_column,
+            self.table,
+            self.alias,
+            self.keys_predicate(number_of_keys)
+        )
+    }
+
+    fn keys_predicate(&self, number_of_keys: usize) -> String {
+        let (target, tuple) = if self.columns.len() == 1 {
+            (self.qualified().join(""), String::from("?"))
+        } else {
+            (
+                format!("({})", self.qualified().join(", ")),
+                format!("({})", vec!["?"; self.columns.len()].join(", ")),
+            )
+        };
+        format!("{} IN ({})", target, vec![tuple; number_of_keys].join(", "))
+    }
+
+    fn qualified(&self) -> Vec<String> {
+        self.columns
+            .iter()
+            .map(|c| format!("{}.{}", self.alias, c))
+            .collect()
+    }
+}
+
+/// Returns true, if the column list of the insert contains all key columns.
+///
+/// Auto increment keys are not inserted.
+pub(crate) fn inserts_columns(insert_sql: &str, columns: &[String]) -> bool {
+    let list = match (insert_sql.find('('), insert_sql.find(')')) {
+        (Some(start), Some(end)) if start < end => &insert_sql[start + 1..end],
+        _ => return false,
+    };
+    let inserted = list
+        .split(',')
+        .map(|c| c.trim().trim_matches('`'))
+        .collect::<Vec<_>>();
+    columns
+        .iter()
+        .all(|c| inserted.contains(&c.trim_matches('`')))
+}
+
+/// Comparable representation of a key.
+///
+/// Numbers from the database and from entities may differ in signedness.
+/// Strings are quoted and escaped, so that different keys never have the same representation.
+pub(crate) fn key_repr(key: &[SqlArg]) -> String {
+    key.iter()
+        .map(|a| match a {
+            SqlArg::U64(d) => d.to_string(),
+            SqlArg::I64(d) => d.to_string(),
+            SqlArg::F64(d) => d.to_string(),
+            SqlArg::Str(d) => format!("'{}'", d.replace('\\', "\\\\").replace('\'', "\\'")),
+            SqlArg::Bool(d) => (*d as u8).to_string(),
+            SqlArg::Null() => String::from("NULL"),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use toql::sql_arg::SqlArg;
+
+    use super::{inserts_columns, key_repr, MergedRows};
+
+    #[test]
+    fn builds_key_statements() {
+        let columns = vec!["user_id".to_string(), "number".to_string()];
+        let rows = MergedRows {
+            table: "Phone",
+            alias: "user_phones",
+            columns: &columns,
+        };
+        assert_eq!(
+            rows.select_keys_sql("JOIN User user ON (user.id = user_phones.user_id)"),
+            "SELECT user_phones.user_id, user_phones.number FROM Phone user_phones \
+             JOIN User user ON (user.id = user_phones.user_id) FOR UPDATE"
+        );
+        assert_eq!(
+            rows.delete_keys_sql(2),
+            "DELETE user_phones FROM Phone user_phones \
+             WHERE (user_phones.user_id, user_phones.number) IN ((?, ?), (?, ?))"
+        );
+    }
+
+    #[test]
+    fn builds_delete_of_single_column_keys() {
+        let columns = vec!["id".to_string()];
+        let rows = MergedRows {
+            table: "Phone",
+            alias: "user_phones",
+            columns: &columns,
+        };
+        assert_eq!(
+            rows.delete_keys_sql(3),
+            "DELETE user_phones FROM Phone user_phones WHERE user_phones.id IN (?, ?, ?)"
+        );
+        assert_eq!(
+            rows.select_deleted_sql("deleted_at", 2),
+            "SELECT user_phones.deleted_at FROM Phone user_phones \
+             WHERE user_phones.id IN (?, ?) FOR UPDATE"
+        );
+    }
+
+    #[test]
+    fn detects_inserted_key_columns() {
+        let sql = "INSERT INTO Phone (`user_id`, number) VALUES (?, ?)";
+        let keys = vec!["user_id".to_string(), "number".to_string()];
+        assert!(inserts_columns(sql, &keys));
+        assert!(!inserts_columns(sql, &["id".to_string()]));
+        assert!(!inserts_columns("INSERT INTO Phone", &keys));
+    }
+
+    #[test]
+    fn represents_keys_independent_of_signedness() {
+        assert_eq!(key_repr(&[SqlArg::U64(5)]), key_repr(&[SqlArg::I64(5)]));
+        assert_eq!(
+            key_repr(&[SqlArg::from("a"), SqlArg::Bool(true), SqlArg::Null()]),
+            "'a',1,NULL"
+        );
+    }
+
+    #[test]
+    fn represents_keys_with_quotes_distinctly() {
+        assert_ne!(
+            key_repr(&[SqlArg::from("a','b"), SqlArg::from("c")]),
+            key_repr(&[SqlArg::from("a"), SqlArg::from("b','c")])
+        );
+        assert_ne!(
+            key_repr(&[SqlArg::from("a\\"), SqlArg::from("b")]),
+            key_repr(&[SqlArg::from("a\\',b")])
+        );
+        assert_eq!(key_repr(&[SqlArg::from("it's\\")]), "'it\\'s\\\\'");
+    }
+}
@@ -0,0 +1,328 @@
+//! Statement metrics.
+//!
+//! A [MetricsSink](trait.MetricsSink.html) on [MySql](../struct.MySql.html) is invoked after every statement
+//! with the entity type, the statement kind, the path, the statement fingerprint, the duration and the number of rows.
+//!
+//! [PrometheusMetrics](struct.PrometheusMetrics.html) aggregates the metrics in memory
+//! and renders them in the Prometheus text format.
+//!
+//! ```ignore
+//! let metrics = PrometheusMetrics::new();
+//! let mut toql = MySql::from(&mut conn, &cache);
+//! toql.set_metrics(Some(&metrics));
+//! let users = toql.load_many(query!(User, "*, phones_*"))?;
+//! println!("{}", metrics.render()?);
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::Duration,
+};
+
+use toql::error::ToqlError;
+
+use crate::error::Result;
+
+/// Kind of a statement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum StatementKind {
+    /// Root select of a load
+    Select,
+    /// Merge select of a load
+    Merge,
+    /// Count select
+    Count,
+    Insert,
+    Update,
+    Delete,
+}
+
+impl StatementKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StatementKind::Select => "select",
+            StatementKind::Merge => "merge",
+            StatementKind::Count => "count",
+            StatementKind::Insert => "insert",
+            StatementKind::Update => "update",
+            StatementKind::Delete => "delete",
+        }
+    }
+}
+
+/// Metrics of an executed statement.
+#[derive(Debug, Clone)]
+pub struct StatementMetrics<'a> {
+    /// Type name of the root entity, or the table name for audit inserts.
+    pub type_name: &'a str,
+    pub kind: StatementKind,
+    /// Field path of the statement, empty for the root entity.
+    pub path: &'a str,
+    /// Normalized SQL, see [fingerprint](fn.fingerprint.html).
+    pub fingerprint: &'a str,
+    pub duration: Duration,
+    /// Rows read for selects and affected rows for inserts, updates and deletes.
+    pub rows: u64,
+}
+
+/// Receiver of statement metrics.
+pub trait MetricsSink: Send + Sync {
+    fn record(&self, metrics: &StatementMetrics);
+}
+
+/// Normalize SQL to group statements that only differ in their values.
+///
+/// Whitespace is collapsed, numbers and strings become `?`
+/// and lists of placeholders become `?+`.
+pub fn fingerprint(sql: &str) -> String {
+    let mut normalized = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    let mut last_word = false;
+
+    while let Some(c) = chars.next() {
+        if c.is_whitespace() {
+            if !normalized.ends_with(' ') && !normalized.is_empty() {
+                normalized.push(' ');
+            }
+            last_word = false;
+            continue;
+        }
+        if c == '\'' || c == '"' {
+            while let Some(n) = chars.next() {
+                if n == '\\' {
+                    chars.next();
+                } else if n == c {
+                    break;
+                }
+            }
+            normalized.push('?');
+            last_word = false;
+            continue;
+        }
+        if c.is_ascii_digit() && !last_word {
+            while chars
+                .peek()
+                .map_or(false, |n| n.is_ascii_digit() || *n == '.')
+            {
+                chars.next();
+            }
+            normalized.push('?');
+            continue;
+        }
+        last_word = c.is_alphanumeric() || c == '_';
+        normalized.push(c);
+    }
+
+    collapse_placeholders(normalized.trim_end())
+}
+
+/// Replace lists like `?, ?, ?` with `?+`
+fn collapse_placeholders(sql: &str) -> String {
+    let mut collapsed = String::with_capacity(sql.len());
+    let mut rest = sql;
+    while let Some(pos) = rest.find('?') {
+        collapsed.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        let mut repeated = false;
+        loop {
+            let trimmed = rest.trim_start();
+            if let Some(after_comma) = trimmed.strip_prefix(',') {
+                let after_comma = after_comma.trim_start();
+                if let Some(after) = after_comma.strip_prefix('?') {
+                    rest = after;
+                    repeated = true;
+                    continue;
+                }
+            }
+            break;
+        }
+        collapsed.push_str(if repeated { "?+" } else { "?" });
+    }
+    collapsed.push_str(rest);
+    collapsed
+}
+
+/// Hash of a fingerprint, useful as short label.
+///
+/// The hash is FNV-1a and stays the same across processes and releases.
+pub fn fingerprint_hash(fingerprint: &str) -> String {
+    let hash = fingerprint
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+            (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+        });
+    format!("{:016x}", hash)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct Labels {
+    type_name: String,
+    kind: StatementKind,
+    path: String,
+    fingerprint: String,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Aggregate {
+    count: u64,
+    rows: u64,
+    duration_sum: f64,
+    buckets: Vec<u64>,
+}
+
+/// In-memory aggregator that renders metrics in the Prometheus text format.
+///
+/// Durations are recorded in a histogram `toql_statement_duration_seconds`,
+/// rows in a counter `toql_statement_rows_total`.
+pub struct PrometheusMetrics {
+    bounds: Vec<f64>,
+    aggregates: Mutex<BTreeMap<Labels, Aggregate>>,
+}
+
+impl Default for PrometheusMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PrometheusMetrics {
+    /// Create aggregator with default histogram buckets from 1ms to 10s.
+    pub fn new() -> Self {
+        Self::with_buckets(vec![
+            0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+        ])
+    }
+
+    /// Create aggregator with custom histogram bucket bounds in seconds.
+    pub fn with_buckets(mut bounds: Vec<f64>) -> Self {
+        bounds.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        PrometheusMetrics {
+            bounds,
+            aggregates: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Remove all aggregated metrics.
+    pub fn reset(&self) -> Result<()> {
+        self.aggregates.lock().map_err(ToqlError::from)?.clear();
+        Ok(())
+    }
+
+    /// Render all metrics in the Prometheus text format.
+    pub fn render(&self) -> Result<String> {
+        let aggregates = self.aggregates.lock().map_err(ToqlError::from)?;
+        let mut out = String::new();
+
+        out.push_str("# HELP toql_statement_duration_seconds Duration of Toql statements.\n");
+        out.push_str("# TYPE toql_statement_duration_seconds histogram\n");
+        for (labels, aggregate) in aggregates.iter() {
+            let l = render_labels(labels);
+            let mut cumulative = 0;
+            for (bound, count) in self.bounds.iter().zip(aggregate.buckets.iter()) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "toql_statement_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    l, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "toql_statement_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                l, aggregate.count
+            );
+            let _ = writeln!(
+                out,
+                "toql_statement_duration_seconds_sum{{{}}} {}",
+                l, aggregate.duration_sum
+            );
+            let _ = writeln!(
+                out,
+                "toql_statement_duration_seconds_count{{{}}} {}",
+                l, aggregate.count
+            );
+        }
+
+        out.push_str("# HELP toql_statement_rows_total Rows read or affected by Toql statements.\n");
+        out.push_str("# TYPE toql_statement_rows_total counter\n");
+        for (labels, aggregate) in aggregates.iter() {
+            let _ = writeln!(
+                out,
+                "toql_statement_rows_total{{{}}} {}",
+                render_labels(labels),
+                aggregate.rows
+            );
+        }
+        Ok(out)
+    }
+}
+
+impl MetricsSink for PrometheusMetrics {
+    fn record(&self, metrics: &StatementMetrics) {
+        let labels = Labels {
+            type_name: metrics.type_name.to_string(),
+            kind: metrics.kind,
+            path: metrics.path.to_string(),
+            fingerprint: fingerprint_hash(metrics.fingerprint),
+        };
+        let seconds = metrics.duration.as_secs_f64();
+
+        // Metrics must not fail statements, skip recording on poisoned lock
+        if let Ok(mut aggregates) = self.aggregates.lock() {
+            let aggregate = aggregates.entry(labels).or_insert_with(|| Aggregate {
+                buckets: vec![0; self.bounds.len()],
+                ..Aggregate::default()
+            });
+            aggregate.count += 1;
+            aggregate.rows += metrics.rows;
+            aggregate.duration_sum += seconds;
+            if let Some(i) = self.bounds.iter().position(|b| seconds <= *b) {
+                aggregate.buckets[i] += 1;
+            }
+        }
+    }
+}
+
+fn render_labels(labels: &Labels) -> String {
+    format!(
+        "type=\"{}\",kind=\"{}\",path=\"{}\",fingerprint=\"{}\"",
+        escape_label(&labels.type_name),
+        labels.kind.as_str(),
+        escape_label(&labels.path),
+        labels.fingerprint
+    )
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fingerprint, fingerprint_hash};
+
+    #[test]
+    fn normalizes_values_and_whitespace() {
+        assert_eq!(
+            fingerprint(
+                "SELECT  user.id\n FROM User user WHERE user.name = 'O\\'Hara' AND user.age > 30"
+            ),
+            "SELECT user.id FROM User user WHERE user.name = ? AND user.age > ?"
+        );
+        assert_eq!(
+            fingerprint("SELECT t1.id FROM User t1 WHERE t1.id IN (1, 2, ?, 4.5)"),
+            "SELECT t1.id FROM User t1 WHERE t1.id IN (?+)"
+        );
+    }
+
+    #[test]
+    fn hashes_with_fnv_1a() {
+        assert_eq!(fingerprint_hash(""), "cbf29ce484222325");
+        assert_eq!(fingerprint_hash("a"), "af63dc4c8601ec8c");
+    }
+}
@@ -0,0 +1,192 @@
+//! Mock connection for unit tests.
+//!
+//! A [MockConnection](struct.MockConnection.html) records every statement with its params
+//! and answers with scripted results in the order they were pushed.
+//! Without scripted results selects return no rows
+//! and inserts report one affected row per value tuple with synthesized ids.
+//!
+//! Requires feature `mock`.
+//!
+//! ```ignore
+//! let mut conn = MockConnection::new();
+//! conn.push_rows(vec![vec![Value::from(1), Value::from("Alice")]]);
+//!
+//! let mut toql = MySql::from(&mut conn, &cache);
+//! let user = toql.load_one(query!(User, "id eq 1, name"))?;
+//!
+//! assert_eq!(conn.statements()[0].params, vec![Value::from(1)]);
+//! ```
+
+use std::{collections::VecDeque, sync::Arc};
+
+use mysql::{
+    consts::ColumnType,
+    myc::{packets::column_from_payload, row::new_row},
+    Column, Value,
+};
+
+use crate::{connection::Connection, dry_run::count_insert_rows, error::Result, error::ToqlMySqlError};
+
+/// Statement that was run on a mock connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockStatement {
+    pub sql: String,
+    pub params: Vec<Value>,
+}
+
+/// Scripted result of a mock connection.
+#[derive(Debug)]
+pub enum MockResult {
+    /// Rows of a select, each row is a list of column values.
+    Rows(Vec<Vec<Value>>),
+    /// Affected rows and last insert id of an insert, update or delete.
+    Affected(u64, u64),
+    /// Error returned from the statement.
+    Error(ToqlMySqlError),
+}
+
+/// Connection that records statements and returns scripted results.
+#[derive(Debug)]
+pub struct MockConnection {
+    statements: Vec<MockStatement>,
+    results: VecDeque<MockResult>,
+    next_id: u64,
+    in_transaction: bool,
+}
+
+impl Default for MockConnection {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockConnection {
+    pub fn new() -> Self {
+        MockConnection {
+            statements: Vec::new(),
+            results: VecDeque::new(),
+            next_id: 1,
+            in_transaction: false,
+        }
+    }
+
+    /// Add result rows for the next statement.
+    pub fn push_rows(&mut self, rows: Vec<Vec<Value>>) -> &mut Self {
+        self.results.push_back(MockResult::Rows(rows));
+        self
+    }
+
+    /// Add affected rows and last insert id for the next statement.
+    pub fn push_affected(&mut self, affected_rows: u64, last_insert_id: u64) -> &mut Self {
+        self.results
+            .push_back(MockResult::Affected(affected_rows, last_insert_id));
+        self
+    }
+
+    /// Add error for the next statement.
+    pub fn push_error(&mut self, error: ToqlMySqlError) -> &mut Self {
+        self.results.push_back(MockResult::Error(error));
+        self
+    }
+
+    /// Behave like an open transaction, so that transactions use savepoints.
+    pub fn set_in_transaction(&mut self, in_transaction: bool) -> &mut Self {
+        self.in_transaction = in_transaction;
+        self
+    }
+
+    /// Returns all recorded statements in execution order.
+    pub fn statements(&self) -> &[MockStatement] {
+        &self.statements
+    }
+
+    /// Returns and removes all recorded statements.
+    pub fn take_statements(&mut self) -> Vec<MockStatement> {
+        std::mem::take(&mut self.statements)
+    }
+
+    /// Returns the number of scripted results that have not been consumed.
+    pub fn pending_results(&self) -> usize {
+        self.results.len()
+    }
+
+    fn record(&mut self, sql: &str, params: Vec<Value>) -> Option<MockResult> {
+        self.statements.push(MockStatement {
+            sql: sql.to_string(),
+            params,
+        });
+        self.results.pop_front()
+    }
+}
+
+impl Connection for MockConnection {
+    fn select(&mut self, sql: &str, params: Vec<Value>) -> Result<Vec<mysql::Row>> {
+        match self.record(sql, params) {
+            Some(MockResult::Rows(rows)) => Ok(to_rows(rows)),
+            Some(MockResult::Error(e)) => Err(e),
+            Some(MockResult::Affected(..)) | None => Ok(Vec::new()),
+        }
+    }
+
+    fn execute(&mut self, sql: &str, params: Vec<Value>) -> Result<(u64, u64)> {
+        match self.record(sql, params) {
+            Some(MockResult::Affected(affected_rows, last_insert_id)) => {
+                Ok((affected_rows, last_insert_id))
+            }
+            Some(MockResult::Error(e)) => Err(e),
+            Some(MockResult::Rows(_)) => Ok((0, 0)),
+            None => {
+                let rows = if sql.trim_start().starts_with("INSERT") {
+                    count_insert_rows(sql)
+                } else {
+                    0
+                };
+                let id = self.next_id;
+                self.next_id += rows;
+                Ok((rows, if rows > 0 { id } else { 0 }))
+            }
+        }
+    }
+
+    /// Records the statement without consuming a scripted result.
+    fn query_drop(&mut self, sql: &str) -> Result<()> {
+        self.statements.push(MockStatement {
+            sql: sql.to_string(),
+            params: Vec::new(),
+        });
+        Ok(())
+    }
+
+    fn in_transaction(&self) -> bool {
+        self.in_transaction
+    }
+}
+
+/// Build driver rows with columns `c0`, `c1`, ...
+fn to_rows(rows: Vec<Vec<Value>>) -> Vec<mysql::Row> {
+    let width = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+    let columns = Arc::new((0..width).map(|i| column(&format!("c{}", i))).collect::<Vec<_>>());
+    rows.into_iter()
+        .map(|mut values| {
+            values.resize(width, Value::NULL);
+            new_row(values, columns.clone())
+        })
+        .collect()
+}
+
+/// Build a column from a column definition packet.
+fn column(name: &str) -> Column {
+    let mut payload = Vec::new();
+    for s in &["def", "", "", "", name, name] {
+        payload.push(s.len() as u8); // Length encoded, names are short
+        payload.extend_from_slice(s.as_bytes());
+    }
+    payload.push(0x0c);
+    payload.extend_from_slice(&33u16.to_le_bytes()); // utf8_general_ci
+    payload.extend_from_slice(&255u32.to_le_bytes());
+    payload.push(ColumnType::MYSQL_TYPE_VAR_STRING as u8);
+    payload.extend_from_slice(&0u16.to_le_bytes()); // flags
+    payload.push(0); // decimals
+    payload.extend_from_slice(&[0, 0]);
+    column_from_payload(payload).expect("valid column definition")
+}
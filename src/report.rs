@@ -0,0 +1,188 @@
+//! Reports of mutations.
+//!
+//! An [InsertReport](struct.InsertReport.html) tells what `insert_many` actually did:
+//! the generated ids and the affected rows per path and all statements in execution order.
+//! An [UpdateReport](struct.UpdateReport.html) does the same for `update_many`
+//! with the updated rows per path and the deleted and inserted rows of merges.
+//!
+//! ```ignore
+//! let report = toql.insert_many(paths!(User, "address"), &mut users)?;
+//! println!("User ids {:?}", report.ids(""));
+//! println!("Address rows {}", report.affected_rows("address"));
+//!
+//! let report = toql.update_many(fields!(User, "name, phones"), &mut users)?;
+//! println!("Phones deleted {}, inserted {}", report.deleted("phones"), report.inserted("phones"));
+//! ```
+//!
+//! A [SyncReport](struct.SyncReport.html) combines the reports of `sync_many`.
+
+use std::collections::BTreeMap;
+
+use toql::sql::Sql;
+
+use crate::{dry_run::RecordedStatement, metrics::StatementKind};
+
+/// Outcome of an insert.
+///
+/// Paths are field paths, the root path is empty.
+#[derive(Default)]
+pub struct InsertReport {
+    /// Ids that MySQL reports per path, consecutive from the last insert id of every statement.
+    /// They match the inserted entities only, if no entity was skipped and no row was updated instead.
+    /// Paths without auto increment keys are missing.
+    pub generated_ids: BTreeMap<String, Vec<u64>>,
+    /// Affected rows per path.
+    pub affected_rows: BTreeMap<String, u64>,
+    /// Executed statements in execution order.
+    pub statements: Vec<RecordedStatement>,
+}
+
+impl InsertReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the generated ids of a path.
+    pub fn ids(&self, path: &str) -> &[u64] {
+        self.generated_ids
+            .get(path)
+            .map(|i| i.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Returns the last generated id of the root entities.
+    pub fn last_insert_id(&self) -> Option<u64> {
+        self.ids("").last().copied()
+    }
+
+    /// Returns the affected rows of a path.
+    pub fn affected_rows(&self, path: &str) -> u64 {
+        self.affected_rows.get(path).copied().unwrap_or(0)
+    }
+
+    /// Returns the affected rows of all paths.
+    pub fn total_rows(&self) -> u64 {
+        self.affected_rows.values().sum()
+    }
+
+    /// Record an executed insert.
+    ///
+    /// A multi row insert generates consecutive ids starting with the last insert id.
+    pub(crate) fn record(
+        &mut self,
+        path: &str,
+        sql: Sql,
+        affected_rows: u64,
+        last_insert_id: u64,
+    ) {
+        *self.affected_rows.entry(path.to_string()).or_insert(0) += affected_rows;
+        if last_insert_id > 0 {
+            self.generated_ids
+                .entry(path.to_string())
+                .or_insert_with(Vec::new)
+                .extend(last_insert_id..last_insert_id + affected_rows);
+        }
+        self.statements.push(RecordedStatement {
+            kind: StatementKind::Insert,
+            path: path.to_string(),
+            sql,
+        });
+    }
+}
+
+/// Outcome of an update.
+///
+/// Paths are field paths, the root path is empty.
+/// Updated rows only count changed rows, unless the connection is opened with `CLIENT_FOUND_ROWS`.
+#[derive(Default)]
+pub struct UpdateReport {
+    /// Updated rows per path.
+    pub updated_rows: BTreeMap<String, u64>,
+    /// Deleted rows per merge path.
+    pub deleted_rows: BTreeMap<String, u64>,
+    /// Inserted rows per merge path.
+    pub inserted_rows: BTreeMap<String, u64>,
+    /// Executed statements in execution order.
+    pub statements: Vec<RecordedStatement>,
+}
+
+impl UpdateReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the updated rows of a path.
+    pub fn updated(&self, path: &str) -> u64 {
+        self.updated_rows.get(path).copied().unwrap_or(0)
+    }
+
+    /// Returns the deleted rows of a merge path.
+    pub fn deleted(&self, path: &str) -> u64 {
+        self.deleted_rows.get(path).copied().unwrap_or(0)
+    }
+
+    /// Returns the inserted rows of a merge path.
+    pub fn inserted(&self, path: &str) -> u64 {
+        self.inserted_rows.get(path).copied().unwrap_or(0)
+    }
+
+    /// Returns the affected rows of all paths.
+    pub fn total_rows(&self) -> u64 {
+        self.updated_rows.values().sum::<u64>()
+            + self.deleted_rows.values().sum::<u64>()
+            + self.inserted_rows.values().sum::<u64>()
+    }
+
+    /// Add the rows and statements of another report.
+    pub(crate) fn extend(&mut self, other: UpdateReport) {
+        for (path, rows) in other.updated_rows {
+            *self.updated_rows.entry(path).or_insert(0) += rows;
+        }
+        for (path, rows) in other.deleted_rows {
+            *self.deleted_rows.entry(path).or_insert(0) += rows;
+        }
+        for (path, rows) in other.inserted_rows {
+            *self.inserted_rows.entry(path).or_insert(0) += rows;
+        }
+        self.statements.extend(other.statements);
+    }
+
+    /// Record an executed update, delete or insert.
+    pub(crate) fn record(&mut self, kind: StatementKind, path: &str, sql: Sql, affected_rows: u64) {
+        let rows = match kind {
+            StatementKind::Delete => &mut self.deleted_rows,
+            StatementKind::Insert => &mut self.inserted_rows,
+            _ => &mut self.updated_rows,
+        };
+        *rows.entry(path.to_string()).or_insert(0) += affected_rows;
+        self.statements.push(RecordedStatement {
+            kind,
+            path: path.to_string(),
+            sql,
+        });
+    }
+}
+
+/// Outcome of a collection sync.
+#[derive(Default)]
+pub struct SyncReport {
+    /// Report of the inserted entities.
+    pub inserted: InsertReport,
+    /// Report of the changed entities.
+    pub updated: UpdateReport,
+    /// Number of deleted entities.
+    pub deleted: u64,
+}
+
+impl SyncReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true, if nothing was changed.
+    pub fn is_empty(&self) -> bool {
+        self.inserted.statements.is_empty()
+            && self.updated.statements.is_empty()
+            && self.deleted == 0
+    }
+}
@@ -0,0 +1,294 @@
+//! In-process result cache for loads.
+//!
+//! The [ResultCache](struct.ResultCache.html) keeps the selected rows of `load_one`, `load_many` and `load_page`
+//! in a least recently used cache with a time to live. Cache hits build the entities from the cached rows.
+//! Entries are keyed by the Toql query, the page, the roles, the aux params and the options that change the loaded rows,
+//! such as load options, included deleted rows and read routing.
+//! Locking loads and loads within a transaction bypass the cache, they must read the current rows.
+//!
+//! Every entry remembers the tables that its selects read.
+//! Inserts, updates and deletes through a [MySql](../struct.MySql.html) with the same result cache
+//! invalidate all entries that read a written table, after the statement succeeded.
+//! Writes from other sources must be invalidated manually with [invalidate](struct.ResultCache.html#method.invalidate).
+
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use toql::{error::ToqlError, page::Page, sql_arg::SqlArg};
+
+use crate::{diff::scan, error::Result};
+
+struct Entry {
+    tables: HashSet<String>,
+    value: Box<dyn Any + Send + Sync>,
+    inserted: Instant,
+    last_used: u64,
+}
+
+struct Entries {
+    entries: HashMap<String, Entry>,
+    clock: u64,
+}
+
+/// Least recently used cache with time to live for load results.
+pub struct ResultCache {
+    capacity: usize,
+    ttl: Duration,
+    inner: Mutex<Entries>,
+}
+
+impl ResultCache {
+    /// Create a cache that holds up to `capacity` results for at most `ttl`.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        ResultCache {
+            capacity,
+            ttl,
+            inner: Mutex::new(Entries {
+                entries: HashMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Build cache key from type, query, page, roles, aux params and further options of the load.
+    pub fn key(
+        type_name: &str,
+        query: &str,
+        page: Option<&Page>,
+        roles: &HashSet<String>,
+        aux_params: &HashMap<String, SqlArg>,
+        options: &str,
+    ) -> String {
+        let page = match page {
+            Some(Page::Counted(start, number)) => format!("counted({},{})", start, number),
+            Some(Page::Uncounted(start, number)) => format!("uncounted({},{})", start, number),
+            None => String::from("all"),
+        };
+        let mut roles = roles.iter().map(|r| r.as_str()).collect::<Vec<_>>();
+        roles.sort();
+        let mut aux_params = aux_params
+            .iter()
+            .map(|(k, v)| (k.as_str(), format!("{:?}", v)))
+            .collect::<Vec<_>>();
+        aux_params.sort();
+        // Debug output quotes and escapes strings, so that different parts cannot collide
+        format!("{:?}", (type_name, query, page, roles, aux_params, options))
+    }
+
+    /// Returns a clone of the cached value, if present and not expired.
+    pub fn get<V>(&self, key: &str) -> Result<Option<V>>
+    where
+        V: Clone + 'static,
+    {
+        let mut inner = self.inner.lock().map_err(ToqlError::from)?;
+        let expired = match inner.entries.get(key) {
+            Some(entry) => entry.inserted.elapsed() > self.ttl,
+            None => return Ok(None),
+        };
+        if expired {
+            inner.entries.remove(key);
+            return Ok(None);
+        }
+        inner.clock += 1;
+        let clock = inner.clock;
+        let entry = inner.entries.get_mut(key).unwrap();
+        entry.last_used = clock;
+        Ok(entry.value.downcast_ref::<V>().cloned())
+    }
+
+    /// Insert value that was read from tables. The least recently used entry is evicted, if the cache is full.
+    pub fn insert<V>(&self, tables: HashSet<String>, key: String, value: V) -> Result<()>
+    where
+        V: Send + Sync + 'static,
+    {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let mut inner = self.inner.lock().map_err(ToqlError::from)?;
+        let ttl = self.ttl;
+        inner.entries.retain(|_, e| e.inserted.elapsed() <= ttl);
+        if !inner.entries.contains_key(&key) && inner.entries.len() >= self.capacity {
+            let lru = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.to_owned());
+            if let Some(lru) = lru {
+                inner.entries.remove(&lru);
+            }
+        }
+        inner.clock += 1;
+        let clock = inner.clock;
+        inner.entries.insert(
+            key,
+            Entry {
+                tables,
+                value: Box::new(value),
+                inserted: Instant::now(),
+                last_used: clock,
+            },
+        );
+        Ok(())
+    }
+
+    /// Remove all entries that read a table.
+    pub fn invalidate(&self, table: &str) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(ToqlError::from)?;
+        inner.entries.retain(|_, e| !e.tables.contains(table));
+        Ok(())
+    }
+
+    /// Remove all entries.
+    pub fn clear(&self) -> Result<()> {
+        let mut inner = self.inner.lock().map_err(ToqlError::from)?;
+        inner.entries.clear();
+        Ok(())
+    }
+
+    /// Returns the number of cached results, including expired ones.
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.inner.lock().map_err(ToqlError::from)?.entries.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+/// Returns the tables that a statement reads or writes.
+///
+/// Tables follow the keywords `FROM`, `JOIN`, `INTO` and `UPDATE` outside of string literals.
+pub(crate) fn statement_tables(sql: &str) -> HashSet<String> {
+    let bytes = sql.as_bytes();
+    let mut tables = HashSet::new();
+    scan(sql, |i, _, _| {
+        if i > 0 && is_word_byte(bytes[i - 1]) {
+            return;
+        }
+        for keyword in &["FROM", "JOIN", "INTO", "UPDATE"] {
+            let end = i + keyword.len();
+            if !sql[i..].starts_with(keyword) || !bytes.get(end).map_or(false, |b| b.is_ascii_whitespace()) {
+                continue;
+            }
+            let rest = sql[end..].trim_start_matches(|c: char| c.is_whitespace() || c == '(');
+            let table = rest
+                .split(|c: char| c.is_whitespace() || c == '(' || c == ')' || c == ',' || c == ';')
+                .next()
+                .unwrap_or("")
+                .trim_matches('`');
+            if !table.is_empty() && table != "SELECT" {
+                tables.insert(table.to_string());
+            }
+        }
+    });
+    tables
+}
+
+pub(crate) fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'$'
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        time::Duration,
+    };
+
+    use toql::sql_arg::SqlArg;
+
+    use super::{statement_tables, ResultCache};
+
+    fn tables(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = ResultCache::new(2, Duration::from_secs(60));
+        cache
+            .insert(tables(&["User"]), "a".to_string(), 1u64)
+            .unwrap();
+        cache
+            .insert(tables(&["User"]), "b".to_string(), 2u64)
+            .unwrap();
+        assert_eq!(cache.get::<u64>("a").unwrap(), Some(1));
+
+        cache
+            .insert(tables(&["User"]), "c".to_string(), 3u64)
+            .unwrap();
+        assert_eq!(cache.len().unwrap(), 2);
+        assert_eq!(cache.get::<u64>("b").unwrap(), None);
+        assert_eq!(cache.get::<u64>("a").unwrap(), Some(1));
+        assert_eq!(cache.get::<u64>("c").unwrap(), Some(3));
+    }
+
+    #[test]
+    fn expires_and_invalidates_entries() {
+        let cache = ResultCache::new(10, Duration::from_secs(0));
+        cache
+            .insert(tables(&["User"]), "a".to_string(), 1u64)
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(cache.get::<u64>("a").unwrap(), None);
+
+        let cache = ResultCache::new(10, Duration::from_secs(60));
+        cache
+            .insert(tables(&["User"]), "a".to_string(), 1u64)
+            .unwrap();
+        cache
+            .insert(tables(&["Phone"]), "b".to_string(), 2u64)
+            .unwrap();
+        cache.invalidate("User").unwrap();
+        assert_eq!(cache.get::<u64>("a").unwrap(), None);
+        assert_eq!(cache.get::<u64>("b").unwrap(), Some(2));
+        assert_eq!(cache.get::<String>("b").unwrap(), None);
+    }
+
+    #[test]
+    fn distinguishes_keys_by_all_parts() {
+        let roles = HashSet::new();
+        let mut aux_params = HashMap::new();
+        let key = |query: &str, aux_params: &HashMap<String, SqlArg>, options: &str| {
+            ResultCache::key("User", query, None, &roles, aux_params, options)
+        };
+        let plain = key("*", &aux_params, "");
+        assert_eq!(plain, key("*", &aux_params, ""));
+        assert_ne!(plain, key("*", &aux_params, "FOR UPDATE"));
+        assert_ne!(key("a|b", &aux_params, ""), key("a", &aux_params, "b"));
+        aux_params.insert("tenant".to_string(), SqlArg::U64(1));
+        assert_ne!(plain, key("*", &aux_params, ""));
+    }
+
+    #[test]
+    fn finds_statement_tables() {
+        assert_eq!(
+            statement_tables(
+                "SELECT user.id FROM `User` user JOIN (Address user_address) ON (1 = 1) \
+                 WHERE user.name = 'FROM Phone'"
+            ),
+            tables(&["User", "Address"])
+        );
+        assert_eq!(
+            statement_tables("INSERT INTO Phone (number) SELECT number FROM OldPhone"),
+            tables(&["Phone", "OldPhone"])
+        );
+        assert_eq!(
+            statement_tables("UPDATE User user SET user.name = ?"),
+            tables(&["User"])
+        );
+        assert!(statement_tables("SELECT 1").is_empty());
+    }
+}
@@ -0,0 +1,37 @@
+//! Read routing between primary and replica connections.
+//!
+//! Loads and counts can be served from a replica connection, while inserts, updates and deletes
+//! always run on the primary connection.
+//! Within a transaction loads and counts read from the primary connection as well,
+//! so that they see the uncommitted writes of the transaction.
+
+/// Determines which connection serves loads and counts.
+///
+/// Without a replica connection all statements run on the primary connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadPreference {
+    /// Read from replica.
+    Replica,
+    /// Read from primary.
+    Primary,
+    /// Read from replica until the first insert, update or delete, then read from primary.
+    /// This allows to read your own writes within a request.
+    PrimaryAfterWrite,
+}
+
+impl Default for ReadPreference {
+    fn default() -> Self {
+        ReadPreference::Replica
+    }
+}
+
+impl ReadPreference {
+    /// Returns true, if reads must go to the primary connection.
+    pub fn reads_primary(&self, written: bool) -> bool {
+        match self {
+            ReadPreference::Replica => false,
+            ReadPreference::Primary => true,
+            ReadPreference::PrimaryAfterWrite => written,
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! Session initialisation for connections.
+//!
+//! [SessionSettings](struct.SessionSettings.html) describe session variables that every connection must have,
+//! such as time zone, SQL mode, `group_concat_max_len` and transaction isolation level.
+//!
+//! For pools the settings become init statements of the connection options,
+//! so the driver runs them on every new physical connection, also after a reconnect.
+//! With feature `r2d2` the settings can also be used as connection customizer.
+//! Single connections apply and verify the settings directly.
+//!
+//! ```ignore
+//! let settings = SessionSettings::new()
+//!     .with_time_zone("+00:00")
+//!     .with_sql_mode("STRICT_ALL_TABLES,NO_ZERO_DATE")
+//!     .with_isolation_level(IsolationLevel::ReadCommitted);
+//! let mut builder = OptsBuilder::from_opts(Opts::from_url(url)?);
+//! settings.apply_to_opts(&mut builder);
+//! let pool = mysql::Pool::new(builder)?;
+//! ```
+
+use std::collections::HashSet;
+
+use mysql::{prelude::GenericConnection, IsolationLevel, OptsBuilder};
+use toql::error::ToqlError;
+
+use crate::error::{Result, ToqlMySqlError};
+
+/// Session variables and init statements for connections.
+#[derive(Debug, Clone, Default)]
+pub struct SessionSettings {
+    time_zone: Option<String>,
+    sql_mode: Option<String>,
+    group_concat_max_len: Option<u64>,
+    isolation_level: Option<IsolationLevel>,
+    statements: Vec<String>,
+}
+
+impl SessionSettings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set time zone, e.g. `+00:00`.
+    pub fn with_time_zone<S: Into<String>>(mut self, time_zone: S) -> Self {
+        self.time_zone = Some(time_zone.into());
+        self
+    }
+
+    /// Set SQL mode, e.g. `STRICT_ALL_TABLES,NO_ZERO_DATE`.
+    pub fn with_sql_mode<S: Into<String>>(mut self, sql_mode: S) -> Self {
+        self.sql_mode = Some(sql_mode.into());
+        self
+    }
+
+    /// Set maximum length for `GROUP_CONCAT`.
+    pub fn with_group_concat_max_len(mut self, len: u64) -> Self {
+        self.group_concat_max_len = Some(len);
+        self
+    }
+
+    /// Set transaction isolation level for the session.
+    pub fn with_isolation_level(mut self, isolation_level: IsolationLevel) -> Self {
+        self.isolation_level = Some(isolation_level);
+        self
+    }
+
+    /// Add custom init statement. Custom statements run after the session variables are set
+    /// and are not verified.
+    pub fn with_statement<S: Into<String>>(mut self, statement: S) -> Self {
+        self.statements.push(statement.into());
+        self
+    }
+
+    /// Returns all statements to initialize a session.
+    pub fn statements(&self) -> Vec<String> {
+        let mut statements = Vec::new();
+        if let Some(time_zone) = &self.time_zone {
+            statements.push(format!("SET SESSION time_zone = '{}'", escape(time_zone)));
+        }
+        if let Some(sql_mode) = &self.sql_mode {
+            statements.push(format!("SET SESSION sql_mode = '{}'", escape(sql_mode)));
+        }
+        if let Some(len) = self.group_concat_max_len {
+            statements.push(format!("SET SESSION group_concat_max_len = {}", len));
+        }
+        if let Some(level) = &self.isolation_level {
+            statements.push(format!(
+                "SET SESSION TRANSACTION ISOLATION LEVEL {}",
+                isolation_level_sql(level)
+            ));
+        }
+        statements.extend(self.statements.iter().cloned());
+        statements
+    }
+
+    /// Set the statements as init statements of connection options.
+    ///
+    /// The driver runs them on every new physical connection. Other init statements are replaced.
+    pub fn apply_to_opts<'b>(&self, builder: &'b mut OptsBuilder) -> &'b mut OptsBuilder {
+        builder.init(self.statements())
+    }
+
+    /// Apply settings to connection and verify them.
+    pub fn apply<C: GenericConnection>(&self, conn: &mut C) -> Result<()> {
+        for statement in self.statements() {
+            log::info!("SQL `{}`", &statement);
+            conn.query(&statement)?;
+        }
+        self.verify(conn)
+    }
+
+    /// Verify that the session variables of the connection match the settings.
+    pub fn verify<C: GenericConnection>(&self, conn: &mut C) -> Result<()> {
+        if let Some(time_zone) = &self.time_zone {
+            let actual: String = select_value(conn, "SELECT @@SESSION.time_zone")?;
+            if &actual != time_zone {
+                return Err(mismatch("time_zone", time_zone, actual));
+            }
+        }
+        if let Some(sql_mode) = &self.sql_mode {
+            let actual: String = select_value(conn, "SELECT @@SESSION.sql_mode")?;
+            let actual_modes = actual
+                .split(',')
+                .map(|m| m.trim().to_uppercase())
+                .collect::<HashSet<_>>();
+            let missing = sql_mode
+                .split(',')
+                .map(|m| m.trim().to_uppercase())
+                .any(|m| !m.is_empty() && !actual_modes.contains(&m));
+            if missing {
+                return Err(mismatch("sql_mode", sql_mode, actual));
+            }
+        }
+        if let Some(len) = self.group_concat_max_len {
+            let actual: u64 = select_value(conn, "SELECT @@SESSION.group_concat_max_len")?;
+            if actual != len {
+                return Err(mismatch(
+                    "group_concat_max_len",
+                    &len.to_string(),
+                    actual.to_string(),
+                ));
+            }
+        }
+        if let Some(level) = &self.isolation_level {
+            let actual: String = select_value(conn, "SELECT @@SESSION.transaction_isolation")?;
+            let expected = isolation_level_sql(level).replace(' ', "-");
+            if actual != expected {
+                return Err(mismatch("transaction_isolation", &expected, actual));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "r2d2")]
+impl r2d2::CustomizeConnection<mysql::Conn, mysql::Error> for SessionSettings {
+    /// Apply and verify the settings on every new physical connection of an r2d2 pool.
+    fn on_acquire(&self, conn: &mut mysql::Conn) -> std::result::Result<(), mysql::Error> {
+        self.apply(conn).map_err(|e| match e {
+            ToqlMySqlError::MySqlError(e) => e,
+            e => mysql::Error::IoError(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("{:?}", e),
+            )),
+        })
+    }
+}
+
+fn isolation_level_sql(level: &IsolationLevel) -> &'static str {
+    match level {
+        IsolationLevel::ReadUncommitted => "READ UNCOMMITTED",
+        IsolationLevel::ReadCommitted => "READ COMMITTED",
+        IsolationLevel::RepeatableRead => "REPEATABLE READ",
+        IsolationLevel::Serializable => "SERIALIZABLE",
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\'', "''")
+}
+
+fn mismatch(variable: &str, expected: &str, actual: String) -> ToqlMySqlError {
+    ToqlMySqlError::SessionMismatch(variable.to_string(), expected.to_string(), actual)
+}
+
+fn select_value<C, T>(conn: &mut C, sql: &str) -> Result<T>
+where
+    C: GenericConnection,
+    T: mysql::prelude::FromValue,
+{
+    log::info!("SQL `{}`", sql);
+    let value = conn
+        .query(sql)?
+        .into_iter()
+        .next()
+        .transpose()?
+        .and_then(|row| row.get::<T, _>(0));
+    value.ok_or_else(|| ToqlError::NotFound.into())
+}
@@ -0,0 +1,92 @@
+//! Slow query logging.
+//!
+//! Root and merge selects of loads that exceed a threshold are logged on warn level with redacted arguments.
+//! Optionally the query plan is captured with `EXPLAIN FORMAT=JSON` on the same connection
+//! and attached to the log record.
+//!
+//! ```ignore
+//! let mut toql = MySql::from(&mut conn, &cache);
+//! toql.set_slow_query_log(Some(SlowQueryLog::new(Duration::from_millis(500)).with_explain(true)));
+//! ```
+
+use std::time::Duration;
+
+use toql::sql_arg::SqlArg;
+
+use crate::{instrument, metrics::StatementKind};
+
+/// Configuration for slow query logging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlowQueryLog {
+    threshold: Duration,
+    explain: bool,
+}
+
+impl SlowQueryLog {
+    /// Log selects that run longer than the threshold.
+    pub fn new(threshold: Duration) -> Self {
+        SlowQueryLog {
+            threshold,
+            explain: false,
+        }
+    }
+
+    /// Capture query plan for slow selects.
+    ///
+    /// Selects with `SQL_CALC_FOUND_ROWS` are not explained,
+    /// because the explain would reset the found rows for the following count.
+    pub fn with_explain(mut self, explain: bool) -> Self {
+        self.explain = explain;
+        self
+    }
+
+    pub fn threshold(&self) -> Duration {
+        self.threshold
+    }
+
+    pub fn explain(&self) -> bool {
+        self.explain
+    }
+
+    /// Returns true, if the statement is slow.
+    pub fn is_slow(&self, kind: StatementKind, duration: Duration) -> bool {
+        match kind {
+            StatementKind::Select | StatementKind::Merge => duration > self.threshold,
+            _ => false,
+        }
+    }
+
+    /// Returns true, if the plan of the slow statement should be captured.
+    pub fn should_explain(&self, sql: &str) -> bool {
+        self.explain && !sql.contains("SQL_CALC_FOUND_ROWS")
+    }
+}
+
+/// Log slow statement with optional query plan.
+pub(crate) fn log(
+    kind: StatementKind,
+    path: &str,
+    sql: &str,
+    args: &[SqlArg],
+    log_args: bool,
+    duration: Duration,
+    plan: Option<&str>,
+) {
+    let kind = kind.as_str();
+    let args = instrument::format_args(args, log_args);
+    let duration_ms = duration.as_millis() as u64;
+    let plan = plan.unwrap_or("");
+
+    #[cfg(feature = "tracing")]
+    tracing::warn!(kind, path, sql, args = %args, duration_ms, plan, "slow statement");
+    #[cfg(not(feature = "tracing"))]
+    log::warn!(
+        "Slow {} `{}` for path `{}` with {} took {} ms. Plan: {}",
+        kind,
+        sql,
+        path,
+        args,
+        duration_ms,
+        plan
+    );
+}
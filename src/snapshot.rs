@@ -0,0 +1,159 @@
+//! SQL snapshot testing.
+//!
+//! A [SqlSnapshot](struct.SqlSnapshot.html) runs Toql functions against a [MockConnection](../mock/struct.MockConnection.html)
+//! and renders all statements in execution order with their params inlined.
+//! Whitespace is normalized and aliases follow the given [AliasFormat](../../toql/alias/enum.AliasFormat.html),
+//! so the snapshot is deterministic and can be compared against a checked-in file.
+//!
+//! Set the environment variable `TOQL_UPDATE_SNAPSHOTS` to write the current snapshots to their files,
+//! otherwise missing snapshot files fail the assertion.
+//!
+//! Requires feature `mock`.
+//!
+//! ```ignore
+//! let mut conn = MockConnection::new();
+//! let context = Context {
+//!     roles: HashSet::new(),
+//!     aux_params: HashMap::new(),
+//!     alias_format: AliasFormat::Canonical,
+//! };
+//! let (_, snapshot) = SqlSnapshot::record(&mut conn, &cache, context, |toql| {
+//!     toql.load_many(query!(User, "*, phones_*"))
+//! })?;
+//! snapshot.assert_matches("tests/snapshots/load_user.sql");
+//! ```
+
+use std::{fmt, fs, path::Path};
+
+use toql::{backend::context::Context, cache::Cache, sql::Sql};
+
+use crate::{
+    error::Result,
+    mock::{MockConnection, MockStatement},
+    sql_arg::arg_from,
+    MySql,
+};
+
+/// Environment variable to update snapshot files instead of comparing them.
+pub const UPDATE_SNAPSHOTS: &str = "TOQL_UPDATE_SNAPSHOTS";
+
+/// Normalized statements of a recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SqlSnapshot {
+    statements: Vec<String>,
+}
+
+impl SqlSnapshot {
+    /// Run function on a mock connection and return its result and the snapshot of all statements.
+    ///
+    /// The context provides roles, aux params and the alias format.
+    /// Scripted results of the connection are used as usual.
+    pub fn record<F, R>(
+        conn: &mut MockConnection,
+        cache: &Cache,
+        context: Context,
+        f: F,
+    ) -> Result<(R, SqlSnapshot)>
+    where
+        F: FnOnce(&mut MySql<MockConnection>) -> Result<R>,
+    {
+        let result = {
+            let mut mysql = MySql::with_context(&mut *conn, cache, context);
+            f(&mut mysql)?
+        };
+        let snapshot = Self::from_statements(&conn.take_statements());
+        Ok((result, snapshot))
+    }
+
+    /// Build snapshot from recorded statements.
+    pub fn from_statements(statements: &[MockStatement]) -> Self {
+        let statements = statements
+            .iter()
+            .map(|s| {
+                let args = s.params.iter().cloned().map(arg_from).collect();
+                normalize(&Sql(s.sql.to_owned(), args).to_unsafe_string())
+            })
+            .collect();
+        SqlSnapshot { statements }
+    }
+
+    pub fn statements(&self) -> &[String] {
+        &self.statements
+    }
+
+    /// Compare snapshot with the content of a file and panic on difference or a missing file.
+    ///
+    /// With `TOQL_UPDATE_SNAPSHOTS` set the file is written instead.
+    pub fn assert_matches<P: AsRef<Path>>(&self, path: P) {
+        let path = path.as_ref();
+        let actual = self.to_string();
+
+        if std::env::var_os(UPDATE_SNAPSHOTS).is_some() {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).expect("snapshot directory is writable");
+            }
+            fs::write(path, &actual).expect("snapshot file is writable");
+            return;
+        }
+
+        if !path.exists() {
+            panic!(
+                "SQL snapshot `{}` is missing.\n--- actual\n{}\nSet `{}` to write the snapshot.",
+                path.display(),
+                actual.trim_end(),
+                UPDATE_SNAPSHOTS
+            );
+        }
+        let expected = fs::read_to_string(path).expect("snapshot file is readable");
+        if expected.trim_end() != actual.trim_end() {
+            panic!(
+                "SQL snapshot `{}` does not match.\n--- expected\n{}\n--- actual\n{}\nSet `{}` to update the snapshot.",
+                path.display(),
+                expected.trim_end(),
+                actual.trim_end(),
+                UPDATE_SNAPSHOTS
+            );
+        }
+    }
+}
+
+impl fmt::Display for SqlSnapshot {
+    /// One statement per line, each terminated with a semicolon.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for statement in &self.statements {
+            writeln!(f, "{};", statement.trim_end_matches(';'))?;
+        }
+        Ok(())
+    }
+}
+
+/// Collapse whitespace outside of string literals.
+fn normalize(sql: &str) -> String {
+    let mut normalized = String::with_capacity(sql.len());
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for c in sql.trim().chars() {
+        if let Some(q) = quote {
+            normalized.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        if c.is_whitespace() {
+            if !normalized.ends_with(' ') {
+                normalized.push(' ');
+            }
+            continue;
+        }
+        if c == '\'' || c == '"' {
+            quote = Some(c);
+        }
+        normalized.push(c);
+    }
+    normalized
+}
@@ -0,0 +1,447 @@
+//! Soft delete.
+//!
+//! [SoftDelete](struct.SoftDelete.html) designates a deletion timestamp column per mapper.
+//! `delete_one` and `delete_many` on the tables of these mappers set the column to the current time instead of deleting the rows.
+//! Merged rows that `update_many` removes are soft deleted as well and revived, if they are inserted again.
+//! Rows with the inserted keys are read and locked before, an insert with the key of a row that is not deleted
+//! fails with a duplicate key error like without soft delete.
+//! Loads and counts exclude rows with a deletion timestamp from the root select, the merge selects and their joins,
+//! unless deleted rows are requested with `MySql::with_deleted`.
+//!
+//! ```ignore
+//! toql.set_soft_delete(Some(SoftDelete::new().column("User", "deleted_at").column("Phone", "deleted_at")));
+//! toql.delete_one(UserKey::from(5))?;
+//! let all_users = toql.with_deleted(|toql| toql.load_many(query!(User, "*, phones_*")))?;
+//! ```
+
+use std::collections::HashMap;
+
+use toql::{sql::Sql, sql_mapper_registry::SqlMapperRegistry};
+
+use crate::{
+    diff::{find_top_level, scan, DeleteStatement},
+    error::{Result, ToqlMySqlError},
+    result_cache::{is_word_byte, statement_tables},
+};
+
+/// Clauses that end a `WHERE` clause.
+const CLAUSES_AFTER_WHERE: [&str; 6] = [
+    " GROUP BY ",
+    " HAVING ",
+    " ORDER BY ",
+    " LIMIT ",
+    " FOR UPDATE",
+    " LOCK IN SHARE MODE",
+];
+
+/// Deletion timestamp columns by mapper name.
+#[derive(Debug, Clone, Default)]
+pub struct SoftDelete {
+    columns: HashMap<String, String>,
+}
+
+impl SoftDelete {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Soft delete rows of the table of a mapper by setting the timestamp column.
+    pub fn column<M: Into<String>, C: Into<String>>(mut self, mapper: M, column: C) -> Self {
+        self.columns.insert(mapper.into(), column.into());
+        self
+    }
+
+    /// Returns the deletion timestamp column of a mapper.
+    pub fn deleted_column(&self, mapper: &str) -> Option<&str> {
+        self.columns.get(mapper).map(|c| c.as_str())
+    }
+
+    /// Returns the deletion timestamp columns by the tables of the registered mappers.
+    pub(crate) fn tables(&self, registry: &SqlMapperRegistry) -> DeletedColumns {
+        let columns = self
+            .columns
+            .iter()
+            .filter_map(|(mapper, column)| {
+                registry.mappers.get(mapper).map(|m| {
+                    (
+                        m.table_name.trim_matches('`').to_string(),
+                        column.to_owned(),
+                    )
+                })
+            })
+            .collect();
+        DeletedColumns { columns }
+    }
+}
+
+/// Deletion timestamp columns by table name, rewrites the statements of these tables.
+pub(crate) struct DeletedColumns {
+    columns: HashMap<String, String>,
+}
+
+impl DeletedColumns {
+    /// Returns the deletion timestamp column of a table.
+    pub(crate) fn deleted_column(&self, table: &str) -> Option<&str> {
+        self.columns
+            .get(table.trim_matches('`'))
+            .map(|c| c.as_str())
+    }
+
+    /// Exclude deleted rows of the table in the `FROM` clause and of joined tables of a select.
+    ///
+    /// Joined tables get the condition in their `ON` clause, so that deleted rows of left joins read as missing.
+    pub(crate) fn exclude_deleted(&self, sql: &mut String) {
+        let mut joins = join_conditions(sql)
+            .into_iter()
+            .filter_map(|(table, alias, start, end)| {
+                self.deleted_column(table)
+                    .map(|column| (format!("{}.{} IS NULL", alias, column), start, end))
+            })
+            .collect::<Vec<_>>();
+        joins.sort_by_key(|(_, start, _)| std::cmp::Reverse(*start));
+        for (predicate, start, end) in joins {
+            let condition = sql[start..end].trim_end().to_string();
+            let end = start + condition.len();
+            sql.replace_range(start..end, &format!("{} AND ({})", predicate, condition));
+        }
+
+        let from = match find_top_level(sql, " FROM ") {
+            Some(pos) => pos + " FROM ".len(),
+            None => return,
+        };
+        let mut tokens = sql[from..].split_whitespace();
+        let table = match tokens.next() {
+            Some(t) => t,
+            None => return,
+        };
+        let alias = match tokens.next() {
+            Some(a) if !is_keyword(a) => a.trim_end_matches(';'),
+            _ => table,
+        };
+        let predicate = match self.deleted_column(table) {
+            Some(column) => format!("{}.{} IS NULL", alias, column),
+            None => return,
+        };
+        add_predicate(sql, &predicate);
+    }
+
+    /// Turn a delete into an update that sets the deletion timestamp.
+    ///
+    /// Deletes of tables without timestamp column are returned unchanged.
+    /// Fails for a delete of an unexpected form that touches a table with timestamp column,
+    /// rather than deleting its rows physically.
+    pub(crate) fn delete_sql(&self, sql: Sql) -> Result<Sql> {
+        let delete = match DeleteStatement::parse(&sql.0) {
+            Some(d) => d,
+            None if statement_tables(&sql.0)
+                .iter()
+                .all(|t| self.deleted_column(t).is_none()) =>
+            {
+                return Ok(sql)
+            }
+            None => return Err(ToqlMySqlError::UnexpectedStatement(sql.0)),
+        };
+        let update = self.deleted_column(delete.table).map(|column| {
+            let deleted = format!("{}.{}", delete.alias, column);
+            match find_top_level(delete.from, " WHERE ") {
+                Some(pos) => format!(
+                    "UPDATE {} SET {} = NOW() WHERE ({}) AND {} IS NULL",
+                    &delete.from[..pos],
+                    deleted,
+                    &delete.from[pos + " WHERE ".len()..],
+                    deleted
+                ),
+                None => format!(
+                    "UPDATE {} SET {} = NOW() WHERE {} IS NULL",
+                    delete.from, deleted, deleted
+                ),
+            }
+        });
+        Ok(match update {
+            Some(update) => Sql(update, sql.1),
+            None => sql,
+        })
+    }
+
+    /// Returns the clause for an insert into a table with timestamp column,
+    /// that revives soft deleted rows with the same key and leaves other existing rows untouched.
+    ///
+    /// Revived rows take the inserted values. Untouched rows do not count as affected,
+    /// so that callers can detect them from the number of affected rows.
+    pub(crate) fn revive_sql(&self, insert_sql: &str) -> Option<String> {
+        let start = insert_sql.find('(')?;
+        let table = insert_sql[..start].split_whitespace().last()?;
+        let deleted = self.deleted_column(table)?;
+        let end = start + insert_sql[start..].find(')')?;
+        // Assignments see the values of the preceding ones, so the timestamp is reset last
+        let mut set = insert_sql[start + 1..end]
+            .split(',')
+            .map(str::trim)
+            .filter(|c| c.trim_matches('`') != deleted)
+            .map(|c| format!("{} = IF({} IS NULL, {}, VALUES({}))", c, deleted, c, c))
+            .collect::<Vec<_>>();
+        set.push(format!("{} = NULL", deleted));
+        Some(format!(" ON DUPLICATE KEY UPDATE {}", set.join(", ")))
+    }
+}
+
+/// Returns table, alias and the position of the `ON` condition of all joins.
+fn join_conditions(sql: &str) -> Vec<(&str, &str, usize, usize)> {
+    let bytes = sql.as_bytes();
+    let mut joins = Vec::new();
+    for pos in keyword_positions(sql, "JOIN") {
+        let mut i = skip_whitespace(sql, pos + "JOIN".len());
+        // Nested joins are enclosed in parentheses and followed by their condition
+        let group_end = if bytes.get(i) == Some(&b'(') {
+            i += 1;
+            match matching_parenthesis(sql, i - 1) {
+                Some(end) => Some(end),
+                None => continue,
+            }
+        } else {
+            None
+        };
+        let mut tokens = sql[i..].split_whitespace();
+        let table = match tokens.next() {
+            Some(t) => t.trim_end_matches(')'),
+            None => continue,
+        };
+        let alias = match tokens.next() {
+            Some(a) if !a.eq_ignore_ascii_case("ON") => a.trim_end_matches(')'),
+            _ => table,
+        };
+        let after = match group_end {
+            Some(end) => end + 1,
+            None => {
+                let table_end = i + sql[i..].find(table).unwrap_or(0) + table.len();
+                if alias == table {
+                    table_end
+                } else {
+                    table_end + sql[table_end..].find(alias).unwrap_or(0) + alias.len()
+                }
+            }
+        };
+        let on = skip_whitespace(sql, after);
+        if !sql[on..].starts_with("ON")
+            || !bytes
+                .get(on + 2)
+                .map_or(false, |b| b.is_ascii_whitespace() || *b == b'(')
+        {
+            continue;
+        }
+        let start = skip_whitespace(sql, on + 2);
+        joins.push((table, alias, start, condition_end(sql, start)));
+    }
+    joins
+}
+
+/// Returns the positions of a keyword outside of string literals.
+fn keyword_positions(sql: &str, keyword: &str) -> Vec<usize> {
+    let bytes = sql.as_bytes();
+    let mut positions = Vec::new();
+    scan(sql, |i, _, _| {
+        let end = i + keyword.len();
+        if (i == 0 || !is_word_byte(bytes[i - 1]))
+            && sql[i..].starts_with(keyword)
+            && bytes
+                .get(end)
+                .map_or(false, |b| b.is_ascii_whitespace() || *b == b'(')
+        {
+            positions.push(i);
+        }
+    });
+    positions
+}
+
+/// Returns the position of the parenthesis that closes the one at `open`.
+fn matching_parenthesis(sql: &str, open: usize) -> Option<usize> {
+    let mut found = None;
+    scan(&sql[open..], |i, c, depth| {
+        if found.is_none() && c == ')' && depth == 0 {
+            found = Some(open + i);
+        }
+    });
+    found
+}
+
+/// Returns the end of a join condition.
+///
+/// The condition ends before the next clause or join or before the parenthesis that encloses it.
+fn condition_end(sql: &str, start: usize) -> usize {
+    let rest = &sql[start..];
+    let bytes = rest.as_bytes();
+    let mut end = None;
+    scan(rest, |i, c, depth| {
+        if end.is_some() {
+            return;
+        }
+        if c == ')' && depth < 0 {
+            end = Some(i);
+        } else if depth == 0 && (i == 0 || !is_word_byte(bytes[i - 1])) {
+            let starts_clause = CONDITION_ENDS.iter().any(|k| {
+                rest[i..].starts_with(k)
+                    && bytes
+                        .get(i + k.len())
+                        .map_or(false, |b| b.is_ascii_whitespace())
+            });
+            if starts_clause {
+                end = Some(i);
+            }
+        }
+    });
+    start + end.unwrap_or_else(|| rest.trim_end().trim_end_matches(';').len())
+}
+
+/// Keywords that end a join condition.
+const CONDITION_ENDS: [&str; 12] = [
+    "JOIN",
+    "LEFT",
+    "RIGHT",
+    "INNER",
+    "CROSS",
+    "STRAIGHT_JOIN",
+    "WHERE",
+    "GROUP",
+    "HAVING",
+    "ORDER",
+    "LIMIT",
+    "FOR",
+];
+
+fn skip_whitespace(sql: &str, pos: usize) -> usize {
+    pos + sql[pos..].len() - sql[pos..].trim_start().len()
+}
+
+/// Add predicate to the `WHERE` clause of a select.
+fn add_predicate(sql: &mut String, predicate: &str) {
+    let trimmed_len = sql.trim_end().trim_end_matches(';').len();
+    match find_top_level(sql, " WHERE ") {
+        Some(pos) => {
+            let start = pos + " WHERE ".len();
+            let end = clause_end(sql, start).unwrap_or(trimmed_len);
+            sql.insert(end, ')');
+            sql.insert_str(start, &format!("{} AND (", predicate));
+        }
+        None => {
+            let end = clause_end(sql, 0).unwrap_or(trimmed_len);
+            sql.insert_str(end, &format!(" WHERE {}", predicate));
+        }
+    }
+}
+
+/// Returns the position of the first clause that follows the `WHERE` clause.
+fn clause_end(sql: &str, start: usize) -> Option<usize> {
+    CLAUSES_AFTER_WHERE
+        .iter()
+        .filter_map(|c| find_top_level(&sql[start..], c).map(|p| start + p))
+        .min()
+}
+
+fn is_keyword(token: &str) -> bool {
+    let token = token.to_uppercase();
+    [
+        "JOIN", "INNER", "LEFT", "RIGHT", "CROSS", "WHERE", "GROUP", "ORDER", "LIMIT", "USE",
+        "FORCE", "IGNORE", "FOR", "LOCK",
+    ]
+    .contains(&token.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use toql::{sql::Sql, sql_arg::SqlArg};
+
+    use super::DeletedColumns;
+
+    fn soft_delete() -> DeletedColumns {
+        DeletedColumns {
+            columns: vec![("User", "deleted_at"), ("Phone", "deleted_at")]
+                .into_iter()
+                .map(|(t, c)| (t.to_string(), c.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn excludes_deleted_rows_of_root() {
+        let mut sql =
+            "SELECT user.id FROM User user WHERE user.id = ? ORDER BY user.id".to_string();
+        soft_delete().exclude_deleted(&mut sql);
+        assert_eq!(
+            sql,
+            "SELECT user.id FROM User user WHERE user.deleted_at IS NULL AND (user.id = ?) ORDER BY user.id"
+        );
+
+        let mut sql = "SELECT user.id FROM User user LIMIT 10".to_string();
+        soft_delete().exclude_deleted(&mut sql);
+        assert_eq!(
+            sql,
+            "SELECT user.id FROM User user WHERE user.deleted_at IS NULL LIMIT 10"
+        );
+    }
+
+    #[test]
+    fn excludes_deleted_rows_of_joins() {
+        let mut sql = "SELECT user.id FROM Address user_address \
+                       LEFT JOIN (User user) ON (user.address_id = user_address.id) \
+                       JOIN Phone user_phones ON user_phones.user_id = user.id WHERE user_address.id = ?"
+            .to_string();
+        soft_delete().exclude_deleted(&mut sql);
+        assert_eq!(
+            sql,
+            "SELECT user.id FROM Address user_address \
+             LEFT JOIN (User user) ON user.deleted_at IS NULL AND ((user.address_id = user_address.id)) \
+             JOIN Phone user_phones ON user_phones.deleted_at IS NULL AND (user_phones.user_id = user.id) \
+             WHERE user_address.id = ?"
+        );
+    }
+
+    #[test]
+    fn turns_delete_into_update() {
+        let sql = Sql(
+            "DELETE user FROM User user WHERE user.id = ?".to_string(),
+            vec![SqlArg::U64(5)],
+        );
+        let sql = soft_delete().delete_sql(sql).unwrap();
+        assert_eq!(
+            sql.0,
+            "UPDATE User user SET user.deleted_at = NOW() WHERE (user.id = ?) AND user.deleted_at IS NULL"
+        );
+        assert_eq!(sql.1, vec![SqlArg::U64(5)]);
+
+        let sql = Sql(
+            "DELETE user_address FROM Address user_address WHERE user_address.id = ?".to_string(),
+            vec![SqlArg::U64(5)],
+        );
+        let unchanged = soft_delete().delete_sql(sql.clone()).unwrap();
+        assert_eq!(unchanged, sql);
+    }
+
+    #[test]
+    fn rejects_unexpected_delete_of_soft_deleted_table() {
+        let sql = Sql(
+            "DELETE FROM User WHERE id = ?".to_string(),
+            vec![SqlArg::U64(5)],
+        );
+        assert!(soft_delete().delete_sql(sql).is_err());
+        let sql = Sql(
+            "DELETE FROM Address WHERE id = ?".to_string(),
+            vec![SqlArg::U64(5)],
+        );
+        assert!(soft_delete().delete_sql(sql).is_ok());
+    }
+
+    #[test]
+    fn revives_deleted_rows_on_insert() {
+        assert_eq!(
+            soft_delete()
+                .revive_sql("INSERT INTO Phone (user_id, number, deleted_at) VALUES (?, ?, NULL)")
+                .unwrap(),
+            " ON DUPLICATE KEY UPDATE user_id = IF(deleted_at IS NULL, user_id, VALUES(user_id)), \
+             number = IF(deleted_at IS NULL, number, VALUES(number)), deleted_at = NULL"
+        );
+        assert_eq!(
+            soft_delete().revive_sql("INSERT INTO Address (id) VALUES (?)"),
+            None
+        );
+    }
+}
@@ -21,3 +21,28 @@ pub fn value_from(arg: SqlArg) -> Value {
         SqlArg::Null() => Value::NULL,
     }
 }
+
+/// Convert a MySQL value back into an SQL argument.
+///
+/// Bytes become strings, dates and times become strings in MySQL format.
+pub fn arg_from(value: Value) -> SqlArg {
+    match value {
+        Value::NULL => SqlArg::Null(),
+        Value::Int(d) => SqlArg::I64(d),
+        Value::UInt(d) => SqlArg::U64(d),
+        Value::Float(d) => SqlArg::F64(d),
+        Value::Bytes(d) => SqlArg::Str(String::from_utf8_lossy(&d).into_owned()),
+        Value::Date(year, month, day, hour, minute, second, micros) => SqlArg::Str(format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}",
+            year, month, day, hour, minute, second, micros
+        )),
+        Value::Time(negative, days, hours, minutes, seconds, micros) => SqlArg::Str(format!(
+            "{}{:02}:{:02}:{:02}.{:06}",
+            if negative { "-" } else { "" },
+            days * 24 + u32::from(hours),
+            minutes,
+            seconds,
+            micros
+        )),
+    }
+}
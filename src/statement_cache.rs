@@ -0,0 +1,229 @@
+//! Prepared statement cache with statistics.
+//!
+//! The MySQL driver keeps prepared statements per connection in a client side cache
+//! (see `stmt_cache_size` in the connection options), so repeated statements are not prepared again.
+//! A [StatementCache](struct.StatementCache.html) wraps a connection and mirrors this cache by SQL text
+//! with the same bounded size. It counts hits, misses and evictions for all statements that Toql prepares.
+//!
+//! [clear](struct.StatementCache.html#method.clear) closes the prepared statements of the wrapped connection,
+//! e.g. after a schema change. For connections and pooled connections this resets the session with `Conn::reset`,
+//! transactions keep their statements.
+//!
+//! ```ignore
+//! let mut opts = OptsBuilder::from_opts(url);
+//! opts.stmt_cache_size(64);
+//! let mut conn = StatementCache::new(Conn::new(opts)?, 64);
+//!
+//! let mut toql = MySql::from(&mut conn, &cache);
+//! toql.update_many(fields, &mut users)?;
+//! println!("{:?}", conn.stats());
+//! ```
+
+use std::collections::HashMap;
+
+use mysql::Value;
+
+use crate::{connection::Connection, error::Result};
+
+/// Hit and miss counters of a statement cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatementCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub size: usize,
+}
+
+/// Connection with a bounded statement cache keyed by SQL text.
+pub struct StatementCache<C: Connection> {
+    conn: C,
+    capacity: usize,
+    last_used: HashMap<String, u64>,
+    clock: u64,
+    stats: StatementCacheStats,
+}
+
+impl<C: Connection> StatementCache<C> {
+    /// Wrap a connection, whose driver caches up to `capacity` statements.
+    pub fn new(conn: C, capacity: usize) -> Self {
+        StatementCache {
+            conn,
+            capacity,
+            last_used: HashMap::new(),
+            clock: 0,
+            stats: StatementCacheStats::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the current statistics.
+    pub fn stats(&self) -> StatementCacheStats {
+        self.stats
+    }
+
+    /// Close all cached statements, e.g. after a schema change. Counters are kept.
+    pub fn clear(&mut self) -> Result<()> {
+        self.conn.reset_statements()?;
+        self.last_used.clear();
+        self.stats.size = 0;
+        Ok(())
+    }
+
+    /// Reset all counters.
+    pub fn reset_stats(&mut self) {
+        self.stats = StatementCacheStats {
+            size: self.last_used.len(),
+            ..StatementCacheStats::default()
+        };
+    }
+
+    pub fn get_ref(&self) -> &C {
+        &self.conn
+    }
+
+    pub fn get_mut(&mut self) -> &mut C {
+        &mut self.conn
+    }
+
+    pub fn into_inner(self) -> C {
+        self.conn
+    }
+
+    /// Record a statement that was prepared.
+    fn prepared(&mut self, sql: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+
+        if let Some(last_used) = self.last_used.get_mut(sql) {
+            *last_used = clock;
+            self.stats.hits += 1;
+            return;
+        }
+
+        self.stats.misses += 1;
+        if self.capacity == 0 {
+            return;
+        }
+        if self.last_used.len() >= self.capacity {
+            let lru = self
+                .last_used
+                .iter()
+                .min_by_key(|(_, c)| **c)
+                .map(|(s, _)| s.to_owned());
+            if let Some(lru) = lru {
+                self.last_used.remove(&lru);
+                self.stats.evictions += 1;
+            }
+        }
+        self.last_used.insert(sql.to_string(), clock);
+        self.stats.size = self.last_used.len();
+    }
+}
+
+impl<C: Connection> Connection for StatementCache<C> {
+    fn select(&mut self, sql: &str, params: Vec<Value>) -> Result<Vec<mysql::Row>> {
+        let rows = self.conn.select(sql, params)?;
+        self.prepared(sql);
+        Ok(rows)
+    }
+
+    fn execute(&mut self, sql: &str, params: Vec<Value>) -> Result<(u64, u64)> {
+        let result = self.conn.execute(sql, params)?;
+        self.prepared(sql);
+        Ok(result)
+    }
+
+    // Statements without params are not prepared
+    fn query_drop(&mut self, sql: &str) -> Result<()> {
+        self.conn.query_drop(sql)
+    }
+
+    fn in_transaction(&self) -> bool {
+        self.conn.in_transaction()
+    }
+
+    fn reset_statements(&mut self) -> Result<()> {
+        self.clear()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mysql::Value;
+
+    use super::{StatementCache, StatementCacheStats};
+    use crate::{connection::Connection, error::Result};
+
+    #[derive(Default)]
+    struct Counting {
+        executed: usize,
+        resets: usize,
+    }
+
+    impl Connection for Counting {
+        fn select(&mut self, _sql: &str, _params: Vec<Value>) -> Result<Vec<mysql::Row>> {
+            Ok(Vec::new())
+        }
+
+        fn execute(&mut self, _sql: &str, _params: Vec<Value>) -> Result<(u64, u64)> {
+            self.executed += 1;
+            Ok((1, 0))
+        }
+
+        fn query_drop(&mut self, _sql: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn reset_statements(&mut self) -> Result<()> {
+            self.resets += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn counts_hits_misses_and_evictions() {
+        let mut conn = StatementCache::new(Counting::default(), 2);
+        for sql in &["UPDATE a", "UPDATE a", "UPDATE b", "UPDATE c", "UPDATE a"] {
+            conn.execute(sql, Vec::new()).unwrap();
+        }
+        conn.select("SELECT c", Vec::new()).unwrap();
+        conn.query_drop("START TRANSACTION").unwrap();
+
+        assert_eq!(conn.get_ref().executed, 5);
+        assert_eq!(
+            conn.stats(),
+            StatementCacheStats {
+                hits: 1,
+                misses: 5,
+                evictions: 3,
+                size: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn clears_statements_and_keeps_counters() {
+        let mut conn = StatementCache::new(Counting::default(), 10);
+        conn.execute("UPDATE a", Vec::new()).unwrap();
+        conn.execute("UPDATE a", Vec::new()).unwrap();
+        conn.clear().unwrap();
+        assert_eq!(conn.get_ref().resets, 1);
+        assert_eq!(conn.stats().size, 0);
+
+        conn.execute("UPDATE a", Vec::new()).unwrap();
+        assert_eq!(conn.stats().hits, 1);
+        assert_eq!(conn.stats().misses, 2);
+
+        conn.reset_stats();
+        assert_eq!(
+            conn.stats(),
+            StatementCacheStats {
+                size: 1,
+                ..StatementCacheStats::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,486 @@
+#![cfg(feature = "mock")]
+
+//! Empty, single and many entities for the public functions of `MySql`.
+//!
+//! The tests check the kind, the tables and the inlined params of the recorded statements,
+//! so they do not depend on the exact SQL of the Toql version.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+    time::Duration,
+};
+
+use mysql::Value;
+use toql::{
+    alias::AliasFormat,
+    backend::context::Context,
+    cache::Cache,
+    error::ToqlError,
+    page::Page,
+    prelude::{fields, paths, query, Toql},
+    query::Query,
+};
+use toql_mysql::{
+    audit::AuditTrail,
+    error::Result,
+    error::ToqlMySqlError,
+    merge_diff::MergeMode,
+    metrics::{MetricsSink, StatementMetrics},
+    mock::MockConnection,
+    result_cache::ResultCache,
+    snapshot::SqlSnapshot,
+    soft_delete::SoftDelete,
+    MySql,
+};
+
+#[derive(Debug, Default, Clone, PartialEq, Toql)]
+#[toql(auto_key = true)]
+pub struct User {
+    #[toql(key)]
+    id: u64,
+    name: String,
+    #[toql(merge(columns(self = "id", other = "user_id")))]
+    phones: Vec<Phone>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Toql)]
+pub struct Phone {
+    #[toql(key)]
+    user_id: u64,
+    #[toql(key)]
+    number: String,
+}
+
+fn users(n: u64) -> Vec<User> {
+    (1..=n)
+        .map(|id| User {
+            id,
+            name: format!("User {}", id),
+            phones: vec![Phone {
+                user_id: id,
+                number: format!("{}00", id),
+            }],
+        })
+        .collect()
+}
+
+fn user_rows(n: u64) -> Vec<Vec<Value>> {
+    (1..=n)
+        .map(|id| vec![Value::from(id), Value::from(format!("User {}", id))])
+        .collect()
+}
+
+/// Run function on the mock connection with canonical aliases.
+fn record<F, R>(conn: &mut MockConnection, f: F) -> (R, SqlSnapshot)
+where
+    F: FnOnce(&mut MySql<MockConnection>) -> Result<R>,
+{
+    try_record(conn, f).unwrap()
+}
+
+/// Run function like `record` and return its error.
+fn try_record<F, R>(conn: &mut MockConnection, f: F) -> Result<(R, SqlSnapshot)>
+where
+    F: FnOnce(&mut MySql<MockConnection>) -> Result<R>,
+{
+    let cache = Cache::new();
+    SqlSnapshot::record(conn, &cache, context(), f)
+}
+
+/// Context with canonical aliases.
+fn context() -> Context {
+    Context {
+        roles: HashSet::new(),
+        aux_params: HashMap::new(),
+        alias_format: AliasFormat::Canonical,
+    }
+}
+
+/// Metrics sink that keeps the type names of the statements.
+#[derive(Default)]
+struct TypeNames(Mutex<Vec<String>>);
+
+impl MetricsSink for TypeNames {
+    fn record(&self, metrics: &StatementMetrics) {
+        self.0.lock().unwrap().push(metrics.type_name.to_string());
+    }
+}
+
+/// Returns the number of statements that start with a keyword.
+fn count(snapshot: &SqlSnapshot, keyword: &str) -> usize {
+    snapshot
+        .statements()
+        .iter()
+        .filter(|s| s.starts_with(keyword))
+        .count()
+}
+
+#[test]
+fn load_many_without_root_rows_skips_merges() {
+    let mut conn = MockConnection::new();
+    let (users, snapshot) = record(&mut conn, |toql| {
+        toql.load_many(query!(User, "*, phones_*"))
+    });
+    assert!(users.is_empty());
+    assert_eq!(snapshot.statements().len(), 1);
+}
+
+#[test]
+fn load_many_with_single_and_many_rows() {
+    for n in &[1, 3] {
+        let mut conn = MockConnection::new();
+        conn.push_rows(user_rows(*n));
+        let (loaded, snapshot) = record(&mut conn, |toql| {
+            toql.load_many(query!(User, "*, phones_*"))
+        });
+        assert_eq!(loaded.len() as u64, *n);
+        assert_eq!(loaded[0].name, "User 1");
+        // Root select and merge select of the phones
+        assert_eq!(count(&snapshot, "SELECT"), 2);
+        assert!(snapshot.statements()[1].contains("Phone"));
+    }
+}
+
+#[test]
+fn load_one_requires_exactly_one_row() {
+    let mut conn = MockConnection::new();
+    let cache = Cache::new();
+    let mut toql = MySql::from(&mut conn, &cache);
+    match toql.load_one(query!(User, "*, phones_*, id eq 1")) {
+        Err(ToqlMySqlError::ToqlError(ToqlError::NotFound)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let mut conn = MockConnection::new();
+    conn.push_rows(user_rows(1));
+    let (user, snapshot) = record(&mut conn, |toql| {
+        toql.load_one(query!(User, "*, phones_*, id eq 1"))
+    });
+    assert_eq!(user.id, 1);
+    assert_eq!(count(&snapshot, "SELECT"), 2);
+    assert!(snapshot.statements()[0].contains("LIMIT 0,2"));
+
+    let mut conn = MockConnection::new();
+    conn.push_rows(user_rows(3));
+    let mut toql = MySql::from(&mut conn, &cache);
+    match toql.load_one(query!(User, "*, phones_*")) {
+        Err(ToqlMySqlError::ToqlError(ToqlError::NotUnique)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn load_page_with_empty_single_and_many_rows() {
+    for n in &[0, 1, 3] {
+        let mut conn = MockConnection::new();
+        conn.push_rows(user_rows(*n));
+        let ((loaded, count), snapshot) = record(&mut conn, |toql| {
+            toql.load_page(query!(User, "*, phones_*"), Page::Uncounted(0, 10))
+        });
+        assert_eq!(loaded.len() as u64, *n);
+        assert_eq!(count, None);
+        assert!(snapshot.statements()[0].contains("LIMIT 0,10"));
+        let merges = if *n == 0 { 0 } else { 1 };
+        assert_eq!(snapshot.statements().len(), 1 + merges);
+    }
+}
+
+#[test]
+fn load_many_serves_repeated_loads_from_result_cache() {
+    for n in &[0, 1, 3] {
+        let mut conn = MockConnection::new();
+        conn.push_rows(user_rows(*n));
+        let cache = Cache::new();
+        let result_cache = ResultCache::new(10, Duration::from_secs(60));
+        {
+            let mut toql = MySql::builder(&mut conn, &cache)
+                .with_alias_format(AliasFormat::Canonical)
+                .with_result_cache(&result_cache)
+                .build();
+            let loaded = toql.load_many(query!(User, "*, phones_*")).unwrap();
+            assert_eq!(loaded.len() as u64, *n);
+            let cached = toql.load_many(query!(User, "*, phones_*")).unwrap();
+            assert_eq!(cached, loaded);
+        }
+        let snapshot = SqlSnapshot::from_statements(&conn.take_statements());
+        let merges = if *n == 0 { 0 } else { 1 };
+        assert_eq!(count(&snapshot, "SELECT"), 1 + merges);
+        assert_eq!(result_cache.len().unwrap(), 1);
+    }
+}
+
+#[test]
+fn count_returns_number_of_rows() {
+    for n in &[0u64, 1, 3] {
+        let mut conn = MockConnection::new();
+        conn.push_rows(vec![vec![Value::from(*n)]]);
+        let (rows, snapshot) = record(&mut conn, |toql| toql.count(query!(User, "id gt 0")));
+        assert_eq!(rows, *n);
+        assert_eq!(count(&snapshot, "SELECT"), 1);
+    }
+}
+
+#[test]
+fn insert_one_observes_audit_inserts_under_audit_table() {
+    let metrics = TypeNames::default();
+    let cache = Cache::new();
+    let mut conn = MockConnection::new();
+    let mut user = users(1).remove(0);
+    {
+        let mut toql = MySql::with_context(&mut conn, &cache, context());
+        toql.set_metrics(Some(&metrics));
+        toql.set_audit_trail(Some(AuditTrail::new("Audit")));
+        toql.insert_one(paths!(User, "phones"), &mut user).unwrap();
+    }
+    let type_names = metrics.0.into_inner().unwrap();
+    assert_eq!(type_names.iter().filter(|t| *t == "User").count(), 2);
+    assert!(type_names.iter().any(|t| t == "Audit"));
+    assert!(type_names.iter().all(|t| t == "User" || t == "Audit"));
+}
+
+#[test]
+fn insert_many_without_entities_runs_nothing() {
+    let mut conn = MockConnection::new();
+    let mut entities: Vec<User> = Vec::new();
+    let (report, snapshot) = record(&mut conn, |toql| {
+        toql.insert_many::<User, _>(paths!(User, "phones"), &mut entities)
+    });
+    assert_eq!(report.total_rows(), 0);
+    assert!(snapshot.statements().is_empty());
+}
+
+#[test]
+fn insert_one_and_many() {
+    let mut conn = MockConnection::new();
+    let mut user = users(1).remove(0);
+    let (_, snapshot) = record(&mut conn, |toql| {
+        toql.insert_one(paths!(User, "phones"), &mut user)
+    });
+    assert_eq!(count(&snapshot, "INSERT"), 2);
+    assert!(snapshot.statements()[0].contains("'User 1'"));
+    assert!(snapshot.statements()[1].contains("'100'"));
+
+    let mut conn = MockConnection::new();
+    let mut entities = users(3);
+    let (_, snapshot) = record(&mut conn, |toql| {
+        toql.insert_many::<User, _>(paths!(User, "phones"), &mut entities)
+    });
+    // One statement for all users and one for all phones
+    assert_eq!(count(&snapshot, "INSERT"), 2);
+    assert!(snapshot.statements()[0].contains("'User 3'"));
+    assert!(snapshot.statements()[1].contains("'300'"));
+}
+
+#[test]
+fn update_many_without_entities_runs_nothing() {
+    let mut conn = MockConnection::new();
+    let mut entities: Vec<User> = Vec::new();
+    let (report, snapshot) = record(&mut conn, |toql| {
+        toql.update_many::<User, _>(fields!(User, "name, phones"), &mut entities)
+    });
+    assert_eq!(report.total_rows(), 0);
+    assert!(snapshot.statements().is_empty());
+}
+
+#[test]
+fn update_one_and_many() {
+    let mut conn = MockConnection::new();
+    let mut user = users(1).remove(0);
+    let (_, snapshot) = record(&mut conn, |toql| {
+        toql.update_one(fields!(User, "name, phones"), &mut user)
+    });
+    assert_eq!(count(&snapshot, "UPDATE"), 1);
+    // Phones are replaced
+    assert_eq!(count(&snapshot, "DELETE"), 1);
+    assert_eq!(count(&snapshot, "INSERT"), 1);
+
+    let mut conn = MockConnection::new();
+    let mut entities = users(3);
+    let (_, snapshot) = record(&mut conn, |toql| {
+        toql.update_many::<User, _>(fields!(User, "name, phones"), &mut entities)
+    });
+    assert_eq!(count(&snapshot, "UPDATE"), 3);
+    assert_eq!(count(&snapshot, "DELETE"), 1);
+    assert_eq!(count(&snapshot, "INSERT"), 1);
+}
+
+#[test]
+fn update_one_revives_only_soft_deleted_rows() {
+    let soft_delete = || SoftDelete::new().column("Phone", "deleted_at");
+
+    let mut conn = MockConnection::new();
+    conn.push_affected(1, 0)
+        .push_affected(1, 0)
+        .push_rows(vec![vec![Value::from("2020-01-01 00:00:00")]])
+        .push_affected(2, 0);
+    let mut user = users(1).remove(0);
+    let (report, snapshot) = record(&mut conn, |toql| {
+        toql.set_soft_delete(Some(soft_delete()));
+        toql.update_one(fields!(User, "name, phones"), &mut user)
+    });
+    assert_eq!(report.inserted("phones"), 1);
+    assert!(snapshot.statements()[2].ends_with("FOR UPDATE"));
+    assert!(snapshot.statements()[3].contains("ON DUPLICATE KEY UPDATE"));
+
+    // Phone of another user with the same key is not deleted
+    let mut conn = MockConnection::new();
+    conn.push_affected(1, 0)
+        .push_affected(1, 0)
+        .push_rows(vec![vec![Value::NULL]]);
+    let mut user = users(1).remove(0);
+    let result = try_record(&mut conn, |toql| {
+        toql.set_soft_delete(Some(soft_delete()));
+        toql.update_one(fields!(User, "name, phones"), &mut user)
+    });
+    match result {
+        Err(ToqlMySqlError::MySqlError(mysql::Error::MySqlError(e))) => assert_eq!(e.code, 1062),
+        r => panic!("expected duplicate key error, got {:?}", r.map(|_| ())),
+    }
+    assert_eq!(
+        count(&SqlSnapshot::from_statements(conn.statements()), "INSERT"),
+        0
+    );
+}
+
+#[test]
+fn update_many_diffs_all_merges_in_one_transaction() {
+    let mut conn = MockConnection::new();
+    let mut entities = users(3);
+    let (_, snapshot) = record(&mut conn, |toql| {
+        toql.set_merge_mode(MergeMode::Diff);
+        toql.update_many::<User, _>(fields!(User, "name, phones"), &mut entities)
+    });
+    let statements = snapshot.statements();
+    assert_eq!(
+        statements.first().map(|s| s.as_str()),
+        Some("START TRANSACTION")
+    );
+    assert_eq!(statements.last().map(|s| s.as_str()), Some("COMMIT"));
+    assert_eq!(count(&snapshot, "START TRANSACTION"), 1);
+    assert_eq!(count(&snapshot, "SAVEPOINT"), 0);
+    assert_eq!(count(&snapshot, "UPDATE"), 3);
+}
+
+#[test]
+fn update_changed_many_with_empty_single_and_many_entities() {
+    for n in &[0, 1, 3] {
+        let old = users(*n);
+        let mut new = old.clone();
+        new.iter_mut().for_each(|u| u.name.push_str(" changed"));
+
+        let mut conn = MockConnection::new();
+        let (_, snapshot) = record(&mut conn, |toql| {
+            toql.update_changed_many(fields!(User, "name, phones"), &old, &mut new)
+        });
+        // Only the changed names are updated, the phones are unchanged
+        assert_eq!(snapshot.statements().len(), *n as usize);
+        assert_eq!(count(&snapshot, "UPDATE"), *n as usize);
+    }
+}
+
+#[test]
+fn update_changed_without_changes_runs_nothing() {
+    let old = users(1).remove(0);
+    let mut new = old.clone();
+    let mut conn = MockConnection::new();
+    let (report, snapshot) = record(&mut conn, |toql| {
+        toql.update_changed(fields!(User, "name, phones"), &old, &mut new)
+    });
+    assert_eq!(report.total_rows(), 0);
+    assert!(snapshot.statements().is_empty());
+}
+
+#[test]
+fn delete_without_predicate_runs_nothing() {
+    let mut conn = MockConnection::new();
+    let (rows, snapshot) = record(&mut conn, |toql| toql.delete_many(Query::<User>::new()));
+    assert_eq!(rows, 0);
+    assert!(snapshot.statements().is_empty());
+}
+
+#[test]
+fn delete_one_and_many() {
+    let mut conn = MockConnection::new();
+    conn.push_affected(1, 0);
+    let (rows, snapshot) = record(&mut conn, |toql| toql.delete_one(UserKey::from(1)));
+    assert_eq!(rows, 1);
+    assert_eq!(snapshot.statements().len(), 1);
+    assert_eq!(count(&snapshot, "DELETE"), 1);
+
+    let mut conn = MockConnection::new();
+    conn.push_affected(3, 0);
+    let (rows, snapshot) = record(&mut conn, |toql| {
+        toql.delete_many(query!(User, "id in 1;2;3"))
+    });
+    assert_eq!(rows, 3);
+    assert_eq!(snapshot.statements().len(), 1);
+    assert!(snapshot.statements()[0].contains("3"));
+}
+
+#[test]
+fn dry_run_takes_no_locks() {
+    let mut conn = MockConnection::new();
+    record(&mut conn, |toql| {
+        toql.set_dry_run(true);
+        toql.set_audit_trail(Some(AuditTrail::new("Audit")));
+        toql.delete_one(UserKey::from(1))
+    });
+    assert!(conn.statements().is_empty());
+
+    // Merge diffs read the current keys without locking them
+    let mut conn = MockConnection::new();
+    let mut user = users(1).remove(0);
+    record(&mut conn, |toql| {
+        toql.set_dry_run(true);
+        toql.set_merge_mode(MergeMode::Diff);
+        toql.update_one(fields!(User, "name, phones"), &mut user)
+    });
+    assert_eq!(conn.statements().len(), 1);
+    assert!(conn.statements()[0].sql.starts_with("SELECT"));
+    assert!(!conn.statements()[0].sql.contains("FOR UPDATE"));
+}
+
+#[test]
+fn sync_many_with_empty_single_and_many_entities() {
+    for n in &[0, 1, 3] {
+        let outdated = users(*n);
+        let mut updated = users(*n + 1);
+        updated.iter_mut().for_each(|u| u.name.push_str(" changed"));
+
+        let mut conn = MockConnection::new();
+        let (_, snapshot) = record(&mut conn, |toql| {
+            toql.sync_many(
+                &outdated,
+                &mut updated,
+                fields!(User, "name, phones"),
+                paths!(User, "phones"),
+            )
+        });
+        // Last user is inserted with its phone, all others are updated
+        let statements = snapshot.statements();
+        assert_eq!(
+            statements.first().map(|s| s.as_str()),
+            Some("START TRANSACTION")
+        );
+        assert_eq!(statements.last().map(|s| s.as_str()), Some("COMMIT"));
+        assert_eq!(count(&snapshot, "UPDATE"), *n as usize);
+        assert!(statements[1].starts_with("INSERT"));
+        assert_eq!(count(&snapshot, "DELETE"), if *n == 0 { 0 } else { 1 });
+    }
+}
+
+#[test]
+fn transaction_commits_or_uses_savepoint() {
+    let mut conn = MockConnection::new();
+    let (_, snapshot) = record(&mut conn, |toql| toql.transaction(|_| Ok(())));
+    assert_eq!(snapshot.to_string(), "START TRANSACTION;\nCOMMIT;\n");
+
+    let mut conn = MockConnection::new();
+    conn.set_in_transaction(true);
+    let (_, snapshot) = record(&mut conn, |toql| toql.transaction(|_| Ok(())));
+    assert_eq!(
+        snapshot.to_string(),
+        "SAVEPOINT toql_0;\nRELEASE SAVEPOINT toql_0;\n"
+    );
+}
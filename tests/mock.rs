@@ -0,0 +1,84 @@
+#![cfg(feature = "mock")]
+
+use mysql::Value;
+use toql_mysql::{
+    connection::Connection, error::ToqlMySqlError, mock::MockConnection, snapshot::SqlSnapshot,
+};
+
+#[test]
+fn records_statements_with_params() {
+    let mut conn = MockConnection::new();
+    conn.select("SELECT name FROM User WHERE id = ?", vec![Value::from(5)])
+        .unwrap();
+    conn.query_drop("COMMIT").unwrap();
+
+    let statements = conn.statements();
+    assert_eq!(statements.len(), 2);
+    assert_eq!(statements[0].sql, "SELECT name FROM User WHERE id = ?");
+    assert_eq!(statements[0].params, vec![Value::from(5)]);
+    assert_eq!(statements[1].sql, "COMMIT");
+}
+
+#[test]
+fn returns_scripted_results_in_order() {
+    let mut conn = MockConnection::new();
+    conn.push_rows(vec![vec![Value::from(1)], vec![Value::from(2)]])
+        .push_affected(3, 0);
+
+    let rows = conn.select("SELECT id FROM User", Vec::new()).unwrap();
+    let ids = rows
+        .into_iter()
+        .map(|r| r.get::<i64, _>(0).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec![1, 2]);
+    assert_eq!(
+        conn.execute("UPDATE User SET name = ?", vec![Value::from("Bob")])
+            .unwrap(),
+        (3, 0)
+    );
+    assert_eq!(conn.pending_results(), 0);
+}
+
+#[test]
+fn returns_no_rows_without_script() {
+    let mut conn = MockConnection::new();
+    assert!(conn.select("SELECT id FROM User", Vec::new()).unwrap().is_empty());
+}
+
+#[test]
+fn synthesizes_insert_ids() {
+    let mut conn = MockConnection::new();
+    let sql = "INSERT INTO User (name) VALUES (?), (?)";
+    assert_eq!(
+        conn.execute(sql, vec![Value::from("a"), Value::from("b")])
+            .unwrap(),
+        (2, 1)
+    );
+    assert_eq!(conn.execute(sql, vec![Value::from("c"), Value::from("d")]).unwrap(), (2, 3));
+    assert_eq!(conn.execute("DELETE FROM User", Vec::new()).unwrap(), (0, 0));
+}
+
+#[test]
+fn returns_scripted_errors() {
+    let mut conn = MockConnection::new();
+    conn.push_error(ToqlMySqlError::InvalidHint("hint".to_string()));
+    match conn.select("SELECT id FROM User", Vec::new()) {
+        Err(ToqlMySqlError::InvalidHint(h)) => assert_eq!(h, "hint"),
+        r => panic!("unexpected result {:?}", r.map(|r| r.len())),
+    }
+}
+
+#[test]
+fn snapshot_inlines_params_and_normalizes_whitespace() {
+    let mut conn = MockConnection::new();
+    conn.select("SELECT id\n  FROM User   WHERE id = ?", vec![Value::from(5)])
+        .unwrap();
+    conn.execute("DELETE FROM User WHERE id = ?;", vec![Value::from(6)])
+        .unwrap();
+
+    let snapshot = SqlSnapshot::from_statements(conn.statements());
+    assert_eq!(
+        snapshot.to_string(),
+        "SELECT id FROM User WHERE id = 5;\nDELETE FROM User WHERE id = 6;\n"
+    );
+}